@@ -1,34 +1,83 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use quick_xml::de::from_str;
 use quick_xml::se::to_string;
 use serde::{Deserialize, Serialize};
 use std::io::Write;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 struct OME {
+    #[serde(rename = "@xmlns", skip_serializing_if = "Option::is_none")]
+    xmlns: Option<String>,
+    #[serde(rename = "@UUID", skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
     #[serde(rename = "Image", default)]
     images: Vec<Image>,
+    #[serde(
+        rename = "StructuredAnnotations",
+        skip_serializing_if = "Option::is_none"
+    )]
+    structured_annotations: Option<StructuredAnnotations>,
+    #[serde(rename = "BinaryOnly", skip_serializing_if = "Option::is_none")]
+    binary_only: Option<BinaryOnly>,
+}
+
+/// Points a master OME-TIFF holding only pixels to the companion holding its metadata
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct BinaryOnly {
+    #[serde(rename = "@MetadataFile")]
+    metadata_file: String,
+    /// The root UUID of the companion
+    #[serde(rename = "@UUID")]
+    uuid: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Image {
     #[serde(rename = "@ID")]
     id: String,
-    #[serde(rename = "@Name")]
-    name: String,
+    #[serde(rename = "@Name", skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// An xsd:dateTime, with or without a UTC offset
+    #[serde(rename = "AcquisitionDate", skip_serializing_if = "Option::is_none")]
+    acquisition_date: Option<String>,
+    #[serde(rename = "StageLabel", skip_serializing_if = "Option::is_none")]
+    stage_label: Option<StageLabel>,
     #[serde(rename = "Pixels")]
     pixels: Pixels,
+    #[serde(rename = "AnnotationRef", default)]
+    annotation_refs: Vec<AnnotationRef>,
+}
+
+/// The position of the stage when the image was acquired
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct StageLabel {
+    #[serde(rename = "@Name")]
+    name: String,
+    #[serde(rename = "@X", skip_serializing_if = "Option::is_none")]
+    x: Option<f64>,
+    #[serde(rename = "@XUnit", skip_serializing_if = "Option::is_none")]
+    x_unit: Option<LengthUnit>,
+    #[serde(rename = "@Y", skip_serializing_if = "Option::is_none")]
+    y: Option<f64>,
+    #[serde(rename = "@YUnit", skip_serializing_if = "Option::is_none")]
+    y_unit: Option<LengthUnit>,
+    #[serde(rename = "@Z", skip_serializing_if = "Option::is_none")]
+    z: Option<f64>,
+    #[serde(rename = "@ZUnit", skip_serializing_if = "Option::is_none")]
+    z_unit: Option<LengthUnit>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Pixels {
     #[serde(rename = "@ID")]
     id: String,
-    #[serde(rename = "@Type")]
+    #[serde(rename = "@Type", default)]
     r#type: String,
-    #[serde(rename = "@SizeX")]
+    /// Left as 0 when missing, see --fill-dimensions
+    #[serde(rename = "@SizeX", default)]
     size_x: usize,
-    #[serde(rename = "@SizeY")]
+    #[serde(rename = "@SizeY", default)]
     size_y: usize,
     #[serde(rename = "@SizeZ")]
     size_z: usize,
@@ -36,24 +85,227 @@ struct Pixels {
     size_c: usize,
     #[serde(rename = "@SizeT")]
     size_t: usize,
-    #[serde(rename = "@PhysicalSizeX")]
+    #[serde(rename = "@PhysicalSizeX", skip_serializing_if = "Option::is_none")]
     physical_size_x: Option<f64>,
-    #[serde(rename = "@PhysicalSizeXUnit")]
-    physical_size_x_unit: Option<String>,
-    #[serde(rename = "@PhysicalSizeY")]
+    #[serde(rename = "@PhysicalSizeXUnit", skip_serializing_if = "Option::is_none")]
+    physical_size_x_unit: Option<LengthUnit>,
+    #[serde(rename = "@PhysicalSizeY", skip_serializing_if = "Option::is_none")]
     physical_size_y: Option<f64>,
-    #[serde(rename = "@PhysicalSizeYUnit")]
-    physical_size_y_unit: Option<String>,
-    #[serde(rename = "@PhysicalSizeZ")]
+    #[serde(rename = "@PhysicalSizeYUnit", skip_serializing_if = "Option::is_none")]
+    physical_size_y_unit: Option<LengthUnit>,
+    #[serde(rename = "@PhysicalSizeZ", skip_serializing_if = "Option::is_none")]
     physical_size_z: Option<f64>,
-    #[serde(rename = "@PhysicalSizeZUnit")]
-    physical_size_z_unit: Option<String>,
+    #[serde(rename = "@PhysicalSizeZUnit", skip_serializing_if = "Option::is_none")]
+    physical_size_z_unit: Option<LengthUnit>,
     #[serde(rename = "@DimensionOrder")]
-    dimension_order: String,
+    dimension_order: DimensionOrder,
+    /// Whether the samples of a multi-sample channel (RGB) are stored interleaved
+    #[serde(rename = "@Interleaved", skip_serializing_if = "Option::is_none")]
+    interleaved: Option<bool>,
     #[serde(rename = "Channel", default)]
     channels: Vec<Channel>,
+    #[serde(rename = "BinData", default)]
+    bin_data: Vec<BinData>,
     #[serde(rename = "TiffData", default)]
     tiff_data: Vec<TiffData>,
+    #[serde(rename = "Plane", default)]
+    planes: Vec<Plane>,
+    #[serde(rename = "AnnotationRef", default)]
+    annotation_refs: Vec<AnnotationRef>,
+}
+
+impl Pixels {
+    /// Number of channels along C, SizeC counting samples so that an RGB channel
+    /// (SamplesPerPixel=3) takes 3 of it but a single plane per Z and T
+    fn effective_size_c(&self) -> usize {
+        let samples = self
+            .channels
+            .first()
+            .map_or(1, |channel| channel.samples_per_pixel.max(1));
+        (self.size_c / samples).max(1)
+    }
+
+    /// Number of planes, each of them holding all samples of a channel
+    fn plane_count(&self) -> usize {
+        self.size_z * self.effective_size_c() * self.size_t
+    }
+}
+
+/// The order in which planes are stored, from fastest to slowest varying
+/// X and Y are always the two fastest dimensions
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+enum DimensionOrder {
+    XYZCT,
+    XYZTC,
+    XYCTZ,
+    XYCZT,
+    XYTCZ,
+    XYTZC,
+}
+
+impl std::str::FromStr for DimensionOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "XYZCT" => Ok(DimensionOrder::XYZCT),
+            "XYZTC" => Ok(DimensionOrder::XYZTC),
+            "XYCTZ" => Ok(DimensionOrder::XYCTZ),
+            "XYCZT" => Ok(DimensionOrder::XYCZT),
+            "XYTCZ" => Ok(DimensionOrder::XYTCZ),
+            "XYTZC" => Ok(DimensionOrder::XYTZC),
+            _ if !s.starts_with("XY") => Err(format!(
+                "Invalid DimensionOrder \"{}\": X and Y must be the two fastest dimensions (e.g. XYZCT)",
+                s
+            )),
+            _ => Err(format!(
+                "Invalid DimensionOrder \"{}\": expected XY followed by an ordering of Z, C and T",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for DimensionOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            DimensionOrder::XYZCT => "XYZCT",
+            DimensionOrder::XYZTC => "XYZTC",
+            DimensionOrder::XYCTZ => "XYCTZ",
+            DimensionOrder::XYCZT => "XYCZT",
+            DimensionOrder::XYTCZ => "XYTCZ",
+            DimensionOrder::XYTZC => "XYTZC",
+        };
+        f.write_str(s)
+    }
+}
+
+impl Serialize for DimensionOrder {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DimensionOrder {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A unit of length from the OME UnitsLength enumeration, stored as its symbol
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LengthUnit(&'static str);
+
+const LENGTH_UNITS: [&str; 33] = [
+    "Ym",
+    "Zm",
+    "Em",
+    "Pm",
+    "Tm",
+    "Gm",
+    "Mm",
+    "km",
+    "hm",
+    "dam",
+    "m",
+    "dm",
+    "cm",
+    "mm",
+    "µm",
+    "nm",
+    "pm",
+    "fm",
+    "am",
+    "zm",
+    "ym",
+    "Å",
+    "thou",
+    "li",
+    "in",
+    "ft",
+    "yd",
+    "mi",
+    "ua",
+    "ly",
+    "pc",
+    "pixel",
+    "reference frame",
+];
+
+/// Spelled-out SI prefixes and their symbols, for units written like `micro m` or `nanometer`
+const SI_PREFIXES: [(&str, &str); 20] = [
+    ("yotta", "Y"),
+    ("zetta", "Z"),
+    ("exa", "E"),
+    ("peta", "P"),
+    ("tera", "T"),
+    ("giga", "G"),
+    ("mega", "M"),
+    ("kilo", "k"),
+    ("hecto", "h"),
+    ("deca", "da"),
+    ("deci", "d"),
+    ("centi", "c"),
+    ("milli", "m"),
+    ("micro", "µ"),
+    ("nano", "n"),
+    ("pico", "p"),
+    ("femto", "f"),
+    ("atto", "a"),
+    ("zepto", "z"),
+    ("yocto", "y"),
+];
+
+impl std::str::FromStr for LengthUnit {
+    type Err = String;
+
+    /// Accepts the OME symbols, tolerating stray whitespace, the Greek mu (`μm`),
+    /// `um`/`micron`, and spelled-out forms like `micro m` or `nanometer`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lookup = |symbol: &str| LENGTH_UNITS.iter().find(|u| **u == symbol).copied();
+        let collapsed: String = s.split_whitespace().collect::<Vec<_>>().join(" ");
+        let compact: String = collapsed.replace(' ', "").replace('\u{3bc}', "µ");
+        if let Some(unit) = lookup(&collapsed).or_else(|| lookup(&compact)) {
+            return Ok(LengthUnit(unit));
+        }
+        let lower = compact.to_lowercase();
+        let named = match lower.as_str() {
+            "um" | "micron" | "microns" => Some("µm".to_string()),
+            "angstrom" | "angstroms" => Some("Å".to_string()),
+            "meter" | "meters" | "metre" | "metres" => Some("m".to_string()),
+            "inch" | "inches" => Some("in".to_string()),
+            _ => SI_PREFIXES.iter().find_map(|(name, symbol)| {
+                let base = lower.strip_prefix(name)?;
+                matches!(base, "m" | "meter" | "meters" | "metre" | "metres")
+                    .then(|| format!("{}m", symbol))
+            }),
+        };
+        named
+            .and_then(|symbol| lookup(&symbol))
+            .map(LengthUnit)
+            .ok_or_else(|| format!("Unknown length unit \"{}\"", s))
+    }
+}
+
+impl std::fmt::Display for LengthUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Serialize for LengthUnit {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for LengthUnit {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -62,67 +314,717 @@ struct Channel {
     id: String,
     #[serde(rename = "@SamplesPerPixel")]
     samples_per_pixel: usize,
-    #[serde(rename = "@Name")]
-    name: String,
+    #[serde(rename = "@Name", skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(
+        rename = "@ExcitationWavelength",
+        skip_serializing_if = "Option::is_none"
+    )]
+    excitation_wavelength: Option<f64>,
+    /// Defaults to nm when absent
+    #[serde(
+        rename = "@ExcitationWavelengthUnit",
+        skip_serializing_if = "Option::is_none"
+    )]
+    excitation_wavelength_unit: Option<LengthUnit>,
+    #[serde(
+        rename = "@EmissionWavelength",
+        skip_serializing_if = "Option::is_none"
+    )]
+    emission_wavelength: Option<f64>,
+    /// Defaults to nm when absent
+    #[serde(
+        rename = "@EmissionWavelengthUnit",
+        skip_serializing_if = "Option::is_none"
+    )]
+    emission_wavelength_unit: Option<LengthUnit>,
+    /// RGBA packed in a signed 32-bit integer
+    #[serde(rename = "@Color", skip_serializing_if = "Option::is_none")]
+    color: Option<i32>,
+    /// Written before LightPath, as the schema's sequence for Channel requires
+    #[serde(rename = "AnnotationRef", default)]
+    annotation_refs: Vec<AnnotationRef>,
     #[serde(rename = "LightPath")]
     light_path: LightPath,
 }
 
+impl Channel {
+    /// A single-sample channel with a canonical ID, named after that ID
+    fn synthesized(image_index: usize, c: usize) -> Channel {
+        let id = format!("Channel:{}:{}", image_index, c);
+        Channel {
+            name: Some(id.clone()),
+            id,
+            samples_per_pixel: 1,
+            excitation_wavelength: None,
+            excitation_wavelength_unit: None,
+            emission_wavelength: None,
+            emission_wavelength_unit: None,
+            color: None,
+            light_path: LightPath {},
+            annotation_refs: Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct LightPath {}
 
+/// Links an element to an annotation in StructuredAnnotations by ID
+/// An element's references keep their document order, but the transforms drop those
+/// to annotations missing from StructuredAnnotations
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct AnnotationRef {
+    #[serde(rename = "@ID")]
+    id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct StructuredAnnotations {
+    /// The annotations of all types in a single list, in their interleaved document order
+    #[serde(
+        rename = "$value",
+        default,
+        serialize_with = "serialize_known_annotations"
+    )]
+    annotations: Vec<Annotation>,
+}
+
+/// The annotation types of the schema, those the tool doesn't model being kept as read;
+/// elements outside the schema are parsed as `Other` and left out of the output
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(clippy::enum_variant_names)]
+enum Annotation {
+    BooleanAnnotation(ValueAnnotation),
+    CommentAnnotation(ValueAnnotation),
+    DoubleAnnotation(ValueAnnotation),
+    LongAnnotation(ValueAnnotation),
+    TagAnnotation(ValueAnnotation),
+    TermAnnotation(ValueAnnotation),
+    TimestampAnnotation(ValueAnnotation),
+    MapAnnotation(MapAnnotation),
+    FileAnnotation(RawElement),
+    ListAnnotation(RawElement),
+    XMLAnnotation(RawElement),
+    #[serde(other)]
+    Other,
+}
+
+impl Annotation {
+    /// The ID of the annotation, None for the elements outside the schema
+    fn id(&self) -> Option<&str> {
+        match self {
+            Annotation::BooleanAnnotation(a)
+            | Annotation::CommentAnnotation(a)
+            | Annotation::DoubleAnnotation(a)
+            | Annotation::LongAnnotation(a)
+            | Annotation::TagAnnotation(a)
+            | Annotation::TermAnnotation(a)
+            | Annotation::TimestampAnnotation(a) => Some(&a.id),
+            Annotation::MapAnnotation(a) => Some(&a.id),
+            Annotation::FileAnnotation(a)
+            | Annotation::ListAnnotation(a)
+            | Annotation::XMLAnnotation(a) => a.attribute("ID"),
+            Annotation::Other => None,
+        }
+    }
+}
+
+fn serialize_known_annotations<S: serde::Serializer>(
+    annotations: &[Annotation],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        annotations
+            .iter()
+            .filter(|annotation| !matches!(annotation, Annotation::Other)),
+    )
+}
+
+/// An element kept as read: its attributes (`@` and the name), text (`$text`) and
+/// child elements (their name), in document order
+/// The parser drops the namespace prefixes of element names, only their xmlns
+/// attributes are kept
+#[derive(Debug, Clone, Default)]
+struct RawElement {
+    entries: Vec<(String, RawContent)>,
+}
+
+#[derive(Debug, Clone)]
+enum RawContent {
+    Text(String),
+    Element(RawElement),
+}
+
+impl RawElement {
+    /// The value of the attribute `name`
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find_map(|(key, content)| match content {
+                RawContent::Text(value) if key.strip_prefix('@') == Some(name) => {
+                    Some(value.as_str())
+                }
+                _ => None,
+            })
+    }
+}
+
+impl<'de> Deserialize<'de> for RawElement {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = RawElement;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an element")
+            }
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<RawElement, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    let content = if key.starts_with('@') || key == "$text" {
+                        RawContent::Text(map.next_value()?)
+                    } else {
+                        RawContent::Element(map.next_value()?)
+                    };
+                    entries.push((key, content));
+                }
+                Ok(RawElement { entries })
+            }
+        }
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
+impl Serialize for RawElement {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(Some(self.entries.len()))?;
+        for (key, content) in &self.entries {
+            match content {
+                RawContent::Text(text) => map.serialize_entry(key, text)?,
+                RawContent::Element(element) => map.serialize_entry(key, element)?,
+            }
+        }
+        map.end()
+    }
+}
+
+/// An annotation whose Value is a single piece of text
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ValueAnnotation {
+    #[serde(rename = "@ID")]
+    id: String,
+    #[serde(rename = "@Namespace", skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    #[serde(rename = "@Annotator", skip_serializing_if = "Option::is_none")]
+    annotator: Option<String>,
+    #[serde(rename = "Description", skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(rename = "AnnotationRef", default)]
+    annotation_refs: Vec<AnnotationRef>,
+    #[serde(rename = "Value", skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct MapAnnotation {
+    #[serde(rename = "@ID")]
+    id: String,
+    #[serde(rename = "@Namespace", skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    #[serde(rename = "@Annotator", skip_serializing_if = "Option::is_none")]
+    annotator: Option<String>,
+    #[serde(rename = "Description", skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(rename = "AnnotationRef", default)]
+    annotation_refs: Vec<AnnotationRef>,
+    #[serde(rename = "Value")]
+    value: MapValue,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct MapValue {
+    #[serde(rename = "M", default)]
+    entries: Vec<MapEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct MapEntry {
+    #[serde(rename = "@K")]
+    key: String,
+    #[serde(rename = "$text", default)]
+    value: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct TiffData {
-    #[serde(rename = "@IFD")]
+    #[serde(rename = "@IFD", skip_serializing_if = "Option::is_none")]
     ifd: Option<usize>,
-    #[serde(rename = "@PlaneCount")]
+    #[serde(rename = "@PlaneCount", skip_serializing_if = "Option::is_none")]
     plane_count: Option<usize>,
-    #[serde(rename = "@FirstC")]
+    #[serde(rename = "@FirstC", skip_serializing_if = "Option::is_none")]
     first_c: Option<usize>,
-    #[serde(rename = "@FirstZ")]
+    #[serde(rename = "@FirstZ", skip_serializing_if = "Option::is_none")]
     first_z: Option<usize>,
-    #[serde(rename = "@FirstT")]
+    #[serde(rename = "@FirstT", skip_serializing_if = "Option::is_none")]
     first_t: Option<usize>,
-    #[serde(rename = "UUID")]
+    #[serde(rename = "UUID", skip_serializing_if = "Option::is_none")]
     uuid: Option<Uuid>,
 }
 
+/// The file holding the planes of a TiffData, and that file's UUID, as in
+/// `<UUID FileName="stack_z1.ome.tif">urn:uuid:...</UUID>`
+/// FileName is optional in the schema but required here, files are not looked up by UUID
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Uuid {
-    #[serde(rename(serialize = "@FileName", deserialize = "FileName"))]
+    #[serde(rename = "@FileName")]
     file_name: String,
+    /// The `urn:uuid:...` text of the element, absent for generated companions
+    #[serde(rename = "$text", default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+/// A plane embedded in the XML as base64, in the byte order given by BigEndian
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct BinData {
+    #[serde(rename = "@BigEndian")]
+    big_endian: bool,
+    /// Length of the base64 text
+    #[serde(rename = "@Length")]
+    length: usize,
+    #[serde(rename = "@Compression", skip_serializing_if = "Option::is_none")]
+    compression: Option<String>,
+    #[serde(rename = "$text", default)]
+    value: String,
+}
+
+impl BinData {
+    fn encode(bytes: &[u8], big_endian: bool) -> BinData {
+        let value = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, bytes);
+        BinData {
+            big_endian,
+            length: value.len(),
+            compression: None,
+            value,
+        }
+    }
+
+    /// Returns the raw bytes of the plane, still in the byte order given by BigEndian
+    fn decode(&self) -> anyhow::Result<Vec<u8>> {
+        if let Some(compression) = self.compression.as_deref().filter(|c| *c != "none") {
+            anyhow::bail!("Unsupported BinData compression \"{}\"", compression);
+        }
+        let value: String = self.value.split_whitespace().collect();
+        anyhow::ensure!(
+            value.len() == self.length,
+            "BinData Length is {} but its text has {} characters",
+            self.length,
+            value.len()
+        );
+        Ok(base64::Engine::decode(
+            &base64::engine::general_purpose::STANDARD,
+            value,
+        )?)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Plane {
+    #[serde(rename = "@TheZ")]
+    the_z: usize,
+    #[serde(rename = "@TheT")]
+    the_t: usize,
+    #[serde(rename = "@TheC")]
+    the_c: usize,
+    #[serde(rename = "@DeltaT", skip_serializing_if = "Option::is_none")]
+    delta_t: Option<f64>,
+    #[serde(rename = "@DeltaTUnit", skip_serializing_if = "Option::is_none")]
+    delta_t_unit: Option<String>,
+    #[serde(rename = "@ExposureTime", skip_serializing_if = "Option::is_none")]
+    exposure_time: Option<f64>,
+    #[serde(rename = "@ExposureTimeUnit", skip_serializing_if = "Option::is_none")]
+    exposure_time_unit: Option<String>,
+    #[serde(rename = "@PositionX", skip_serializing_if = "Option::is_none")]
+    position_x: Option<f64>,
+    #[serde(rename = "@PositionXUnit", skip_serializing_if = "Option::is_none")]
+    position_x_unit: Option<LengthUnit>,
+    #[serde(rename = "@PositionY", skip_serializing_if = "Option::is_none")]
+    position_y: Option<f64>,
+    #[serde(rename = "@PositionYUnit", skip_serializing_if = "Option::is_none")]
+    position_y_unit: Option<LengthUnit>,
+    #[serde(rename = "@PositionZ", skip_serializing_if = "Option::is_none")]
+    position_z: Option<f64>,
+    #[serde(rename = "@PositionZUnit", skip_serializing_if = "Option::is_none")]
+    position_z_unit: Option<LengthUnit>,
+}
+
+impl Plane {
+    fn new(selection: Selection) -> Plane {
+        Plane {
+            the_z: selection.z,
+            the_t: selection.t,
+            the_c: selection.c,
+            delta_t: None,
+            delta_t_unit: None,
+            exposure_time: None,
+            exposure_time_unit: None,
+            position_x: None,
+            position_x_unit: None,
+            position_y: None,
+            position_y_unit: None,
+            position_z: None,
+            position_z_unit: None,
+        }
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct Selection {
     t: usize,
     z: usize,
     c: usize,
 }
 
+/// Parses a plane given as `z,c,t`, e.g. `3,0,0`
+impl std::str::FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let indices = s
+            .split(',')
+            .map(|i| i.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid plane \"{}\": {}", s, e))?;
+        match indices[..] {
+            [z, c, t] => Ok(Selection { t, z, c }),
+            _ => Err(format!("expected a plane as z,c,t, got \"{}\"", s)),
+        }
+    }
+}
+
 fn get_relative_ifd_index(selection: Selection, pixels: &Pixels) -> usize {
-    let Pixels {
-        size_t,
-        size_c,
-        size_z,
-        ..
-    } = pixels;
+    let Pixels { size_t, size_z, .. } = pixels;
+    let size_c = &pixels.effective_size_c();
     let Selection { t, z, c } = selection;
-    match pixels.dimension_order.as_str() {
-        "XYZCT" => z + (size_z * c) + (size_z * size_c * t),
-        "XYZTC" => z + (size_z * t) + (size_z * size_t * c),
-        "XYCTZ" => c + (size_c * t) + (size_c * size_t * z),
-        "XYCZT" => c + (size_c * z) + (size_c * size_z * t),
-        "XYTCZ" => t + (size_t * c) + (size_t * size_c * z),
-        "XYTZC" => t + (size_t * z) + (size_t * size_z * c),
-        _ => panic!("Invalid dimension order"),
+    match pixels.dimension_order {
+        DimensionOrder::XYZCT => z + (size_z * c) + (size_z * size_c * t),
+        DimensionOrder::XYZTC => z + (size_z * t) + (size_z * size_t * c),
+        DimensionOrder::XYCTZ => c + (size_c * t) + (size_c * size_t * z),
+        DimensionOrder::XYCZT => c + (size_c * z) + (size_c * size_z * t),
+        DimensionOrder::XYTCZ => t + (size_t * c) + (size_t * size_c * z),
+        DimensionOrder::XYTZC => t + (size_t * z) + (size_t * size_z * c),
+    }
+}
+
+/// A single plane of the image and where its pixels are stored
+struct PlaneRef {
+    selection: Selection,
+    ifd: usize,
+    uuid: Option<Uuid>,
+}
+
+/// The file holding a plane: its UUID FileName relative to the OME at `file`, or that
+/// OME itself
+fn plane_file(file: &str, plane: &PlaneRef) -> std::path::PathBuf {
+    let path = std::path::Path::new(file);
+    match &plane.uuid {
+        Some(uuid) => path.with_file_name(&uuid.file_name),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Inverse of `get_relative_ifd_index`, recovering the selection stored at a relative IFD index
+fn ifd_to_selection(ifd: usize, pixels: &Pixels) -> anyhow::Result<Selection> {
+    let Pixels { size_t, size_z, .. } = *pixels;
+    let size_c = pixels.effective_size_c();
+    let plane_count = pixels.plane_count();
+    anyhow::ensure!(
+        ifd < plane_count,
+        "IFD {} is out of range for {} with {} planes",
+        ifd,
+        pixels.id,
+        plane_count
+    );
+    let (fastest, middle) = match pixels.dimension_order {
+        DimensionOrder::XYZCT => (size_z, size_c),
+        DimensionOrder::XYZTC => (size_z, size_t),
+        DimensionOrder::XYCTZ => (size_c, size_t),
+        DimensionOrder::XYCZT => (size_c, size_z),
+        DimensionOrder::XYTCZ => (size_t, size_c),
+        DimensionOrder::XYTZC => (size_t, size_z),
+    };
+    let (i, j, k) = (
+        ifd % fastest,
+        (ifd / fastest) % middle,
+        ifd / (fastest * middle),
+    );
+    let (z, c, t) = match pixels.dimension_order {
+        DimensionOrder::XYZCT => (i, j, k),
+        DimensionOrder::XYZTC => (i, k, j),
+        DimensionOrder::XYCTZ => (k, i, j),
+        DimensionOrder::XYCZT => (j, i, k),
+        DimensionOrder::XYTCZ => (k, j, i),
+        DimensionOrder::XYTZC => (j, k, i),
+    };
+    Ok(Selection { t, z, c })
+}
+
+/// Returns every selection of the image, ordered by relative IFD index
+fn selections_in_order(pixels: &Pixels) -> Vec<Selection> {
+    let plane_count = pixels.plane_count();
+    (0..plane_count)
+        .map(|ifd| ifd_to_selection(ifd, pixels).expect("IFD within plane count"))
+        .collect()
+}
+
+/// Expands the TiffData of the image into the individual planes they reference
+/// Missing attributes follow the OME defaults: IFD and First* default to 0, and
+/// PlaneCount defaults to 1 if an IFD is given, otherwise to all remaining planes
+/// A TiffData with an IFD but none of First* (e.g. `<TiffData IFD="4" PlaneCount="2"/>`)
+/// in `file`, the file holding the OME, starts at the plane the DimensionOrder puts at
+/// that IFD, as writers of such minimal TiffData intend, rather than at the first plane
+fn tiff_data_planes(pixels: &Pixels, file: Option<&str>) -> Vec<PlaneRef> {
+    let selections = selections_in_order(pixels);
+    let mut planes = Vec::new();
+    for tiff_data in &pixels.tiff_data {
+        let first = match (tiff_data.first_t, tiff_data.first_z, tiff_data.first_c) {
+            (None, None, None) if in_own_file(tiff_data, file) => tiff_data
+                .ifd
+                .and_then(|ifd| selections.get(ifd).copied())
+                .unwrap_or(Selection { t: 0, z: 0, c: 0 }),
+            (t, z, c) => Selection {
+                t: t.unwrap_or(0),
+                z: z.unwrap_or(0),
+                c: c.unwrap_or(0),
+            },
+        };
+        let start = get_relative_ifd_index(first, pixels);
+        let plane_count = match (tiff_data.plane_count, tiff_data.ifd) {
+            (Some(n), _) => n,
+            (None, Some(_)) => 1,
+            (None, None) => selections.len().saturating_sub(start),
+        };
+        let ifd = tiff_data.ifd.unwrap_or(0);
+        for (i, selection) in selections.iter().skip(start).take(plane_count).enumerate() {
+            planes.push(PlaneRef {
+                selection: *selection,
+                ifd: ifd + i,
+                uuid: tiff_data.uuid.clone(),
+            });
+        }
+    }
+    planes
+}
+
+/// Whether the planes of a TiffData are stored in `file`, the file holding the OME, its
+/// UUID being absent or naming that file; the IFDs of other files don't follow the
+/// plane order of the image
+fn in_own_file(tiff_data: &TiffData, file: Option<&str>) -> bool {
+    match &tiff_data.uuid {
+        None => true,
+        Some(uuid) => file
+            .and_then(|file| std::path::Path::new(file).file_name())
+            .is_some_and(|name| *name == *uuid.file_name),
+    }
+}
+
+/// Rewrites the pixels to the XYZCT dimension order
+/// The TiffData are recomputed so every plane still maps to the same IFD,
+/// merging runs of consecutive IFDs in the same file into a single entry
+/// `file` being the file the OME was read from
+fn normalize_dimension_order(pixels: &mut Pixels, file: Option<&str>) {
+    let mut planes = tiff_data_planes(pixels, file);
+    pixels.dimension_order = DimensionOrder::XYZCT;
+    planes.sort_by_key(|p| get_relative_ifd_index(p.selection, pixels));
+
+    let mut tiff_data: Vec<TiffData> = Vec::new();
+    let mut prev: Option<(usize, usize)> = None;
+    for plane in planes {
+        let index = get_relative_ifd_index(plane.selection, pixels);
+        if let (Some(last), Some((prev_index, prev_ifd))) = (tiff_data.last_mut(), prev) {
+            let same_file = last.uuid.as_ref().map(|u| &u.file_name)
+                == plane.uuid.as_ref().map(|u| &u.file_name);
+            if same_file && index == prev_index + 1 && plane.ifd == prev_ifd + 1 {
+                last.plane_count = last.plane_count.map(|n| n + 1);
+                prev = Some((index, plane.ifd));
+                continue;
+            }
+        }
+        tiff_data.push(TiffData {
+            ifd: Some(plane.ifd),
+            plane_count: Some(1),
+            first_c: Some(plane.selection.c),
+            first_z: Some(plane.selection.z),
+            first_t: Some(plane.selection.t),
+            uuid: plane.uuid,
+        });
+        prev = Some((index, plane.ifd));
+    }
+    pixels.tiff_data = tiff_data;
+}
+
+/// Drops the AnnotationRefs that point at no annotation in StructuredAnnotations
+fn drop_dangling_annotation_refs(ome: &mut OME, warnings: &Warnings) {
+    let ids: Vec<String> = ome
+        .structured_annotations
+        .iter()
+        .flat_map(|structured| structured.annotations.iter().filter_map(Annotation::id))
+        .map(String::from)
+        .collect();
+    let keep = |owner: &str, refs: &mut Vec<AnnotationRef>| {
+        refs.retain(|annotation_ref| {
+            let known = ids.contains(&annotation_ref.id);
+            if !known {
+                warnings.warn(
+                    "annotation-ref-dropped",
+                    Some(owner),
+                    format!(
+                        "{} references {}, which is missing from StructuredAnnotations, dropping the AnnotationRef",
+                        owner, annotation_ref.id
+                    ),
+                );
+            }
+            known
+        });
+    };
+    for image in ome.images.iter_mut() {
+        keep(&image.id, &mut image.annotation_refs);
+        let pixels = &mut image.pixels;
+        keep(&pixels.id, &mut pixels.annotation_refs);
+        for channel in pixels.channels.iter_mut() {
+            keep(&channel.id, &mut channel.annotation_refs);
+        }
+    }
+}
+
+/// Where the transforms report what they changed or couldn't, without failing
+struct Warnings {
+    /// Print JSON objects instead of `warning: ...` lines
+    json: bool,
+}
+
+/// A warning as printed by `--warnings-json`, or an error of `validate`
+#[derive(Serialize)]
+struct Warning<'a> {
+    /// Kind of warning, e.g. `channels-dropped`, which stays the same across releases
+    code: &'a str,
+    severity: Severity,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    message: &'a str,
+}
+
+impl Warnings {
+    fn warn(&self, code: &str, id: Option<&str>, message: String) {
+        eprintln!("{}", self.line(Severity::Warning, code, id, &message));
+    }
+
+    /// The line printed for a warning, or for an issue of `validate` of any severity
+    fn line(&self, severity: Severity, code: &str, id: Option<&str>, message: &str) -> String {
+        if self.json {
+            let warning = Warning {
+                code,
+                severity,
+                id,
+                message,
+            };
+            serde_json::json!(warning).to_string()
+        } else {
+            format!("{}: {}", severity.label(), message)
+        }
+    }
+}
+
+/// Reconciles the number of Channel elements with SizeC, which some scanners get wrong,
+/// by dropping extra channels or synthesizing the missing ones
+/// SizeC counts samples, so channels of several samples each (RGB) cover several of it
+fn trim_channels_to_size_c(image_index: usize, pixels: &mut Pixels, warnings: &Warnings) {
+    let count = pixels.channels.len();
+    let samples = pixels
+        .channels
+        .first()
+        .map_or(1, |channel| channel.samples_per_pixel.max(1));
+    let expected = pixels.size_c / samples;
+    let size_c = match samples {
+        1 => format!("SizeC={}", pixels.size_c),
+        _ => format!("SizeC={} at SamplesPerPixel={}", pixels.size_c, samples),
+    };
+    if count > expected {
+        warnings.warn(
+            "channels-dropped",
+            Some(&pixels.id),
+            format!(
+                "{} has {} channels but {}, dropping {}",
+                pixels.id,
+                count,
+                size_c,
+                count - expected
+            ),
+        );
+        pixels.channels.truncate(expected);
+    } else if count < expected {
+        warnings.warn(
+            "channels-added",
+            Some(&pixels.id),
+            format!(
+                "{} has {} channels but {}, adding {}",
+                pixels.id,
+                count,
+                size_c,
+                expected - count
+            ),
+        );
+        for c in count..expected {
+            let mut channel = Channel::synthesized(image_index, c);
+            channel.samples_per_pixel = samples;
+            pixels.channels.push(channel);
+        }
+    }
+}
+
+/// Sets the SamplesPerPixel of every channel (e.g. 3 for RGB), then reconciles
+/// the number of channels with SizeC, which counts samples
+fn set_channel_samples(
+    image_index: usize,
+    pixels: &mut Pixels,
+    samples: usize,
+    warnings: &Warnings,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        samples > 0 && pixels.size_c.is_multiple_of(samples),
+        "{} SizeC={} is not a multiple of SamplesPerPixel={}",
+        pixels.id,
+        pixels.size_c,
+        samples
+    );
+    for channel in pixels.channels.iter_mut() {
+        channel.samples_per_pixel = samples;
     }
+    trim_channels_to_size_c(image_index, pixels, warnings);
+    Ok(())
 }
 
 struct StackConfig {
     size_z: usize,
     physical_size_z: f64,
-    physical_size_z_unit: String,
+    physical_size_z_unit: LengthUnit,
     filename_template: String,
+    /// The image the stack is built from
+    image: ImageSelector,
+    /// Planes left out of the TiffData, e.g. known-corrupt acquisitions
+    exclude_planes: Vec<Selection>,
+    /// Emit a single TiffData per file covering all of its planes
+    plane_count_total: bool,
+    /// Emit a Plane per plane, in file order
+    emit_planes: bool,
+    /// Store all SizeC samples interleaved in a single channel, one IFD per z
+    interleaved: bool,
+    /// Emit a Plane per plane positioned at `base + z * physical_size_z`,
+    /// the base being the stage position of the first slice
+    plane_position_z: Option<f64>,
 }
 
 impl StackConfig {
@@ -133,47 +1035,276 @@ impl StackConfig {
     /// e.g. size_z = 10, z = 0, filename = 01
     /// e.g. size_z = 100, z = 0, filename = 001
     /// e.g. size_z = 100, z = 99, filename = 100
-    fn filename(&self, z: usize) -> String {
-        match self.size_z {
-            1..=9 => self
-                .filename_template
-                .replace("{z}", &format!("{:01}", z + 1)),
-            10..=99 => self
-                .filename_template
-                .replace("{z}", &format!("{:02}", z + 1)),
-            100..=999 => self
-                .filename_template
-                .replace("{z}", &format!("{:03}", z + 1)),
-            _ => panic!("Invalid size_z"),
+    fn filename(&self, z: usize) -> anyhow::Result<String> {
+        let width = match self.size_z {
+            1..=9 => 1,
+            10..=99 => 2,
+            100..=999 => 3,
+            size_z => anyhow::bail!("Invalid size_z {}, expected 1 to 999", size_z),
+        };
+        Ok(self
+            .filename_template
+            .replace("{z}", &format!("{:0width$}", z + 1, width = width)))
+    }
+
+    fn is_excluded(&self, selection: Selection) -> bool {
+        self.exclude_planes.contains(&selection)
+    }
+}
+
+/// Counts the files next to `file` whose names match the template with digits in place of `{z}`
+fn discover_stack_size(file: &str, filename_template: &str) -> anyhow::Result<usize> {
+    let (prefix, suffix) = filename_template
+        .split_once("{z}")
+        .ok_or_else(|| anyhow::anyhow!("Filename template has no {{z}} placeholder"))?;
+    let dir = std::path::Path::new(file)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    let mut size_z = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let index = name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix));
+        if matches!(index, Some(index) if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+        {
+            size_z += 1;
+        }
+    }
+    anyhow::ensure!(size_z > 0, "No files match {}", filename_template);
+    Ok(size_z)
+}
+
+/// Overrides SizeX, SizeY and Type with those of the first IFD of `path`
+fn dimensions_from_tiff(path: &std::path::Path, pixels: &mut Pixels) -> anyhow::Result<()> {
+    let reader = std::fs::File::open(path).map(std::io::BufReader::new)?;
+    let mut decoder = tiff::decoder::Decoder::new(reader)?;
+    let (size_x, size_y) = decoder.dimensions()?;
+    pixels.size_x = size_x as usize;
+    pixels.size_y = size_y as usize;
+    pixels.r#type = pixel_type_from_tiff(&mut decoder)?;
+    Ok(())
+}
+
+/// Copies the PhysicalSizes and units of `source` to `target`, failing if `source` has
+/// no PhysicalSizeX or PhysicalSizeY; a missing PhysicalSizeZ leaves that of `target`
+fn copy_physical_sizes(source: &Pixels, target: &mut Pixels) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        source.physical_size_x.is_some() && source.physical_size_y.is_some(),
+        "{} of the physical size reference has no PhysicalSizeX/PhysicalSizeY",
+        source.id
+    );
+    target.physical_size_x = source.physical_size_x;
+    target.physical_size_x_unit = source.physical_size_x_unit;
+    target.physical_size_y = source.physical_size_y;
+    target.physical_size_y_unit = source.physical_size_y_unit;
+    if source.physical_size_z.is_some() {
+        target.physical_size_z = source.physical_size_z;
+        target.physical_size_z_unit = source.physical_size_z_unit;
+    }
+    Ok(())
+}
+
+/// Copies the Name, Color and wavelengths of each channel of `source` to the channel of
+/// `target` at the same index, keeping the IDs and samples of `target`
+fn merge_channels(source: &Pixels, target: &mut Pixels) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        source.channels.len() == target.channels.len(),
+        "{} of the channel reference has {} channels, but {} has {}",
+        source.id,
+        source.channels.len(),
+        target.id,
+        target.channels.len()
+    );
+    for (from, to) in source.channels.iter().zip(target.channels.iter_mut()) {
+        to.name.clone_from(&from.name);
+        to.color = from.color;
+        to.excitation_wavelength = from.excitation_wavelength;
+        to.excitation_wavelength_unit = from.excitation_wavelength_unit;
+        to.emission_wavelength = from.emission_wavelength;
+        to.emission_wavelength_unit = from.emission_wavelength_unit;
+    }
+    Ok(())
+}
+
+/// The image of a reference OME matching image `i`, a single reference image
+/// matching all of them
+fn reference_image<'a>(reference: &'a OME, i: usize, what: &str) -> anyhow::Result<&'a Image> {
+    match &reference.images[..] {
+        [single] => Some(single),
+        images => images.get(i),
+    }
+    .ok_or_else(|| anyhow::anyhow!("The {} reference has no image {}", what, i))
+}
+
+/// Sets a SizeX or SizeY that is 0 (or missing) from the IFD holding the first plane,
+/// `file` being the OME the TiffData file names are relative to
+fn fill_dimensions(file: &str, pixels: &mut Pixels, warnings: &Warnings) -> anyhow::Result<()> {
+    if pixels.size_x != 0 && pixels.size_y != 0 {
+        return Ok(());
+    }
+    let plane = tiff_data_planes(pixels, Some(file))
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{} has no TiffData to fill SizeX/SizeY from", pixels.id))?;
+    let tiff = plane_file(file, &plane);
+    let reader = std::fs::File::open(&tiff).map(std::io::BufReader::new)?;
+    let mut decoder = tiff::decoder::Decoder::new(reader)?;
+    decoder.seek_to_image(plane.ifd)?;
+    let (size_x, size_y) = decoder.dimensions()?;
+    for (axis, size, inferred) in [
+        ("X", &mut pixels.size_x, size_x),
+        ("Y", &mut pixels.size_y, size_y),
+    ] {
+        if *size == 0 {
+            *size = inferred as usize;
+            warnings.warn(
+                "dimension-inferred",
+                Some(&pixels.id),
+                format!(
+                    "{} Size{} set to {} from IFD {} of {}",
+                    pixels.id,
+                    axis,
+                    inferred,
+                    plane.ifd,
+                    tiff.display()
+                ),
+            );
         }
     }
+    Ok(())
 }
 
-fn to_multifile_companion_ome(xml_str: &str, config: &StackConfig) -> anyhow::Result<OME> {
+/// Rewrites the selected image into a companion whose TiffData reference one file per z
+/// The TiffData are emitted by z, then by channel in the order of the Channel elements,
+/// so the output only depends on the input; the model uses Vecs throughout for this
+/// reason, and any map-backed field would have to be sorted before being written
+fn to_multifile_companion_ome(
+    xml_str: &str,
+    config: &StackConfig,
+    warnings: &Warnings,
+) -> anyhow::Result<OME> {
     let mut src: OME = from_str(xml_str)?;
-    let image = src.images.first_mut().unwrap();
+    let index = config.image.resolve(&src)?;
+    let image = &mut src.images[index];
 
     image.pixels.physical_size_z = Some(config.physical_size_z);
-    image.pixels.physical_size_z_unit = Some(config.physical_size_z_unit.clone());
+    image.pixels.physical_size_z_unit = Some(config.physical_size_z_unit);
 
     // Clear out the existing TiffData
     image.pixels.tiff_data.clear();
-    assert_eq!(image.pixels.size_t, 1);
+    anyhow::ensure!(
+        image.pixels.size_t == 1,
+        "{} has SizeT {}, a stack file per z holds a single timepoint",
+        image.pixels.id,
+        image.pixels.size_t
+    );
 
-    for z in 0..config.size_z {
-        for (c, _) in image.pixels.channels.iter().enumerate() {
+    if config.interleaved {
+        let mut channel = match image.pixels.channels.first() {
+            Some(channel) => channel.clone(),
+            None => Channel::synthesized(index, 0),
+        };
+        channel.samples_per_pixel = image.pixels.size_c;
+        image.pixels.channels = vec![channel];
+        image.pixels.interleaved = Some(true);
+    }
+
+    if config.plane_count_total {
+        // Each file holds the channels of a single z in consecutive IFDs, so
+        // C must be the fastest dimension for a PlaneCount to span them
+        let size_c = image.pixels.channels.len();
+        for c in 0..size_c {
             let ifd = get_relative_ifd_index(Selection { t: 0, z: 0, c }, &image.pixels);
-            let tiff_data = TiffData {
-                ifd: Some(ifd),
-                plane_count: Some(1),
-                first_c: Some(c),
-                first_z: Some(z),
-                first_t: Some(0),
-                uuid: Some(Uuid {
-                    file_name: config.filename(z),
-                }),
-            };
-            image.pixels.tiff_data.push(tiff_data);
+            anyhow::ensure!(
+                ifd == c,
+                "Channel {} is not stored in IFD {} of each file",
+                c,
+                c
+            );
+        }
+        // T is a single timepoint, so only the orders storing Z before C need rewriting
+        let order = image.pixels.dimension_order.to_string();
+        if order.find('C') > order.find('Z') {
+            warnings.warn(
+                "dimension-order-changed",
+                Some(&image.pixels.id),
+                format!(
+                    "{} DimensionOrder {} changed to XYCZT, as each file holds the channels of one z",
+                    image.pixels.id, order
+                ),
+            );
+            image.pixels.dimension_order = DimensionOrder::XYCZT;
+        }
+        for z in 0..config.size_z {
+            let uuid = Some(Uuid {
+                file_name: config.filename(z)?,
+                value: None,
+            });
+            if !(0..size_c).any(|c| config.is_excluded(Selection { t: 0, z, c })) {
+                image.pixels.tiff_data.push(TiffData {
+                    ifd: Some(0),
+                    plane_count: Some(size_c),
+                    first_c: Some(0),
+                    first_z: Some(z),
+                    first_t: Some(0),
+                    uuid,
+                });
+                continue;
+            }
+            // A PlaneCount can't skip planes, so the remaining channels get one each
+            for c in (0..size_c).filter(|&c| !config.is_excluded(Selection { t: 0, z, c })) {
+                image.pixels.tiff_data.push(TiffData {
+                    ifd: Some(c),
+                    plane_count: Some(1),
+                    first_c: Some(c),
+                    first_z: Some(z),
+                    first_t: Some(0),
+                    uuid: uuid.clone(),
+                });
+            }
+        }
+    } else {
+        for z in 0..config.size_z {
+            for (c, _) in image.pixels.channels.iter().enumerate() {
+                if config.is_excluded(Selection { t: 0, z, c }) {
+                    continue;
+                }
+                let ifd = get_relative_ifd_index(Selection { t: 0, z: 0, c }, &image.pixels);
+                let tiff_data = TiffData {
+                    ifd: Some(ifd),
+                    plane_count: Some(1),
+                    first_c: Some(c),
+                    first_z: Some(z),
+                    first_t: Some(0),
+                    uuid: Some(Uuid {
+                        file_name: config.filename(z)?,
+                        value: None,
+                    }),
+                };
+                image.pixels.tiff_data.push(tiff_data);
+            }
+        }
+    }
+
+    if config.emit_planes || config.plane_position_z.is_some() {
+        image.pixels.planes.clear();
+        for z in 0..config.size_z {
+            for c in 0..image.pixels.channels.len() {
+                if config.is_excluded(Selection { t: 0, z, c }) {
+                    continue;
+                }
+                let mut plane = Plane::new(Selection { t: 0, z, c });
+                if let Some(base) = config.plane_position_z {
+                    plane.position_z = Some(base + z as f64 * config.physical_size_z);
+                    plane.position_z_unit = Some(config.physical_size_z_unit);
+                }
+                image.pixels.planes.push(plane);
+            }
         }
     }
 
@@ -181,79 +1312,5097 @@ fn to_multifile_companion_ome(xml_str: &str, config: &StackConfig) -> anyhow::Re
     Ok(src)
 }
 
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-#[command(propagate_version = true)]
-struct Cli {
-    #[arg(required = false)]
-    file: Option<String>,
-    #[command(subcommand)]
-    command: Option<Commands>,
+/// A row of `--plane-metadata-csv`, with its line number for error messages
+struct PlaneMetadata {
+    line: usize,
+    selection: Selection,
+    delta_t: Option<f64>,
+    exposure_time: Option<f64>,
+    position_z: Option<f64>,
 }
 
-#[derive(Subcommand)]
-enum Commands {
-    /// Adds files to myapp
-    Concat {
-        // Positional arguments specific to the Concat subcommand
-        #[arg(required = true)]
-        file: String,
-        #[arg(long)]
-        filename_template: String,
-        #[arg(long)]
-        size_z: usize,
-        #[arg(long, default_value_t = 1.0)]
-        physical_size_z: f64,
-        #[arg(long, default_value = "µm")]
-        physical_size_z_unit: String,
-    },
+/// Reads a CSV of per-plane metadata with a header naming its columns: `z`, `c` and
+/// `t` (0-based) and any of `deltaT` and `exposure` (in s) and `posZ`
+/// Empty cells leave the attribute unset
+fn read_plane_metadata_csv(path: &str) -> anyhow::Result<Vec<PlaneMetadata>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty());
+    let (_, header) = lines
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{} is empty", path))?;
+    let columns: Vec<&str> = header.split(',').map(str::trim).collect();
+    for column in &columns {
+        anyhow::ensure!(
+            ["z", "c", "t", "deltaT", "exposure", "posZ"].contains(column),
+            "Unknown column \"{}\" in {}",
+            column,
+            path
+        );
+    }
+    for required in ["z", "c", "t"] {
+        anyhow::ensure!(
+            columns.contains(&required),
+            "{} has no {} column",
+            path,
+            required
+        );
+    }
+    let mut rows = Vec::new();
+    for (line, row) in lines {
+        let cells: Vec<&str> = row.split(',').map(str::trim).collect();
+        anyhow::ensure!(
+            cells.len() == columns.len(),
+            "{}:{} has {} cells for {} columns",
+            path,
+            line,
+            cells.len(),
+            columns.len()
+        );
+        let cell = |name: &str| {
+            columns
+                .iter()
+                .position(|column| *column == name)
+                .map(|i| cells[i])
+                .filter(|cell| !cell.is_empty())
+        };
+        let index = |name: &str| -> anyhow::Result<usize> {
+            let cell =
+                cell(name).ok_or_else(|| anyhow::anyhow!("{}:{} has no {}", path, line, name))?;
+            cell.parse()
+                .map_err(|e| anyhow::anyhow!("{}:{} {} \"{}\": {}", path, line, name, cell, e))
+        };
+        let value = |name: &str| -> anyhow::Result<Option<f64>> {
+            cell(name)
+                .map(|cell| {
+                    cell.parse().map_err(|e| {
+                        anyhow::anyhow!("{}:{} {} \"{}\": {}", path, line, name, cell, e)
+                    })
+                })
+                .transpose()
+        };
+        rows.push(PlaneMetadata {
+            line,
+            selection: Selection {
+                z: index("z")?,
+                c: index("c")?,
+                t: index("t")?,
+            },
+            delta_t: value("deltaT")?,
+            exposure_time: value("exposure")?,
+            position_z: value("posZ")?,
+        });
+    }
+    Ok(rows)
 }
 
-fn get_image_description(file: &str) -> anyhow::Result<String> {
-    let reader = std::fs::File::open(file).map(std::io::BufReader::new)?;
-    let mut decoder = tiff::decoder::Decoder::new(reader)?;
-    if let Some(tiff::decoder::ifd::Value::Ascii(s)) =
-        decoder.find_tag(tiff::tags::Tag::ImageDescription)?
-    {
-        Ok(s)
-    } else {
-        Err(anyhow::anyhow!("No ImageDescription tag found"))
+/// Sets the DeltaT, ExposureTime and PositionZ of the Plane matching each row, failing
+/// for rows without a Plane and for several rows of the same plane
+fn apply_plane_metadata(
+    pixels: &mut Pixels,
+    rows: &[PlaneMetadata],
+    position_z_unit: LengthUnit,
+) -> anyhow::Result<()> {
+    for (i, row) in rows.iter().enumerate() {
+        let Selection { z, c, t } = row.selection;
+        if let Some(first) = rows[..i]
+            .iter()
+            .find(|other| other.selection == row.selection)
+        {
+            anyhow::bail!(
+                "Plane {},{},{} is given on lines {} and {}",
+                z,
+                c,
+                t,
+                first.line,
+                row.line
+            );
+        }
+        let plane = pixels
+            .planes
+            .iter_mut()
+            .find(|plane| (plane.the_z, plane.the_c, plane.the_t) == (z, c, t))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Line {} is for plane {},{},{}, which {} doesn't have",
+                    row.line,
+                    z,
+                    c,
+                    t,
+                    pixels.id
+                )
+            })?;
+        if row.delta_t.is_some() {
+            plane.delta_t = row.delta_t;
+            plane.delta_t_unit = Some("s".to_string());
+        }
+        if row.exposure_time.is_some() {
+            plane.exposure_time = row.exposure_time;
+            plane.exposure_time_unit = Some("s".to_string());
+        }
+        if row.position_z.is_some() {
+            plane.position_z = row.position_z;
+            plane.position_z_unit = Some(position_z_unit);
+        }
     }
+    Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
+const OME_XMLNS: &str = "http://www.openmicroscopy.org/Schemas/OME/2016-06";
+
+/// Schema versions whose semantics the model follows
+const SUPPORTED_SCHEMA_VERSIONS: [&str; 1] = ["2016-06"];
+
+/// The schema version (e.g. `2016-06`) at the end of the root xmlns
+fn schema_version(ome: &OME) -> Option<&str> {
+    ome.xmlns
+        .as_deref()?
+        .strip_prefix("http://www.openmicroscopy.org/Schemas/OME/")
+        .map(|version| version.trim_end_matches('/'))
+}
+
+/// Warns, or fails if `strict`, when the xmlns names another schema than a supported one
+/// A missing xmlns is read as the 2016-06 schema, which is what the model follows
+fn check_schema_version(ome: &OME, strict: bool, warnings: &Warnings) -> anyhow::Result<()> {
+    let Some(xmlns) = &ome.xmlns else {
+        return Ok(());
+    };
+    let (code, message) = match schema_version(ome) {
+        Some(version) if SUPPORTED_SCHEMA_VERSIONS.contains(&version) => return Ok(()),
+        Some(version) => (
+            "unsupported-schema",
+            format!(
+                "OME schema {} is not supported (expected {}), elements may be misread",
+                version,
+                SUPPORTED_SCHEMA_VERSIONS.join(", ")
+            ),
+        ),
+        None => ("unknown-schema", format!("Unknown OME schema {}", xmlns)),
+    };
+    if strict {
+        anyhow::bail!(message);
+    }
+    warnings.warn(code, None, message);
+    Ok(())
+}
+
+struct ImageConfig {
+    size_x: usize,
+    size_y: usize,
+    size_z: usize,
+    size_c: usize,
+    size_t: usize,
+    pixel_type: String,
+    dimension_order: DimensionOrder,
+}
+
+/// Builds a minimal OME with a single image described by `config`
+/// All planes are mapped to consecutive IFDs of a single TIFF,
+/// starting at the first IFD, in the given dimension order
+fn to_minimal_ome(config: &ImageConfig) -> OME {
+    let channels = (0..config.size_c)
+        .map(|c| Channel::synthesized(0, c))
+        .collect();
+    let pixels = Pixels {
+        id: "Pixels:0".to_string(),
+        r#type: config.pixel_type.clone(),
+        size_x: config.size_x,
+        size_y: config.size_y,
+        size_z: config.size_z,
+        size_c: config.size_c,
+        size_t: config.size_t,
+        physical_size_x: None,
+        physical_size_x_unit: None,
+        physical_size_y: None,
+        physical_size_y_unit: None,
+        physical_size_z: None,
+        physical_size_z_unit: None,
+        dimension_order: config.dimension_order,
+        interleaved: None,
+        channels,
+        bin_data: Vec::new(),
+        tiff_data: vec![TiffData {
+            ifd: Some(0),
+            plane_count: Some(config.size_z * config.size_c * config.size_t),
+            first_c: None,
+            first_z: None,
+            first_t: None,
+            uuid: None,
+        }],
+        planes: Vec::new(),
+        annotation_refs: Vec::new(),
+    };
+    OME {
+        xmlns: Some(OME_XMLNS.to_string()),
+        uuid: None,
+        images: vec![Image {
+            id: "Image:0".to_string(),
+            name: Some("Image:0".to_string()),
+            acquisition_date: None,
+            stage_label: None,
+            pixels,
+            annotation_refs: Vec::new(),
+        }],
+        structured_annotations: None,
+        binary_only: None,
+    }
+}
+
+const PIXEL_TYPES: [&str; 11] = [
+    "int8",
+    "int16",
+    "int32",
+    "uint8",
+    "uint16",
+    "uint32",
+    "float",
+    "double",
+    "complex",
+    "double-complex",
+    "bit",
+];
+
+/// Bytes taken by one sample of the pixel type, None for the sub-byte `bit`, see `plane_bytes`
+fn bytes_per_sample(pixel_type: &str) -> Option<usize> {
+    match pixel_type {
+        "int8" | "uint8" => Some(1),
+        "int16" | "uint16" => Some(2),
+        "int32" | "uint32" | "float" => Some(4),
+        "double" | "complex" => Some(8),
+        "double-complex" => Some(16),
+        _ => None,
+    }
+}
+
+/// Name of the numpy dtype holding samples of the pixel type, erroring for a type
+/// that isn't one of the schema's (including an empty one)
+fn numpy_dtype(pixel_type: &str) -> anyhow::Result<&'static str> {
+    Ok(match pixel_type {
+        "int8" => "int8",
+        "int16" => "int16",
+        "int32" => "int32",
+        "uint8" => "uint8",
+        "uint16" => "uint16",
+        "uint32" => "uint32",
+        "float" => "float32",
+        "double" => "float64",
+        "complex" => "complex64",
+        "double-complex" => "complex128",
+        "bit" => "bool",
+        _ => anyhow::bail!("Pixel type \"{}\" has no numpy dtype", pixel_type),
+    })
+}
+
+/// Bytes taken by a single plane of one sample, with `bit` pixels packed 8 to a byte
+fn plane_bytes(pixels: &Pixels) -> Option<usize> {
+    let pixel_count = pixels.size_x * pixels.size_y;
+    match pixels.r#type.as_str() {
+        "bit" => Some(pixel_count.div_ceil(8)),
+        pixel_type => bytes_per_sample(pixel_type).map(|n| n * pixel_count),
+    }
+}
+
+/// Encodes the dimensions of the pixels as a Bio-Formats `.fake` file name,
+/// e.g. `test&sizeZ=10&sizeC=2.fake`
+/// Keys matching the Bio-Formats defaults are left out
+fn to_fake_descriptor(name: &str, pixels: &Pixels) -> String {
+    let mut descriptor = name.to_string();
+    let sizes = [
+        ("sizeX", pixels.size_x, 512),
+        ("sizeY", pixels.size_y, 512),
+        ("sizeZ", pixels.size_z, 1),
+        ("sizeC", pixels.size_c, 1),
+        ("sizeT", pixels.size_t, 1),
+    ];
+    for (key, value, default) in sizes {
+        if value != default {
+            descriptor.push_str(&format!("&{}={}", key, value));
+        }
+    }
+    if pixels.r#type != "uint8" {
+        descriptor.push_str(&format!("&pixelType={}", pixels.r#type));
+    }
+    if pixels.dimension_order != DimensionOrder::XYZCT {
+        descriptor.push_str(&format!("&dimOrder={}", pixels.dimension_order));
+    }
+    descriptor.push_str(".fake");
+    descriptor
+}
+
+/// Overrides the excitation and emission wavelengths of the channels, leaving them
+/// untouched where no wavelengths are given
+fn set_wavelengths(
+    id: &str,
+    pixels: &mut Pixels,
+    excitation: &[f64],
+    emission: &[f64],
+    unit: LengthUnit,
+) -> anyhow::Result<()> {
+    for (flag, values) in [("excitation", excitation), ("emission", emission)] {
+        if !values.is_empty() && values.len() != pixels.channels.len() {
+            anyhow::bail!(
+                "{} has {} channels but {} {} wavelengths were given",
+                id,
+                pixels.channels.len(),
+                values.len(),
+                flag
+            );
+        }
+    }
+    for (c, channel) in pixels.channels.iter_mut().enumerate() {
+        if let Some(&wavelength) = excitation.get(c) {
+            channel.excitation_wavelength = Some(wavelength);
+            channel.excitation_wavelength_unit = Some(unit);
+        }
+        if let Some(&wavelength) = emission.get(c) {
+            channel.emission_wavelength = Some(wavelength);
+            channel.emission_wavelength_unit = Some(unit);
+        }
+    }
+    Ok(())
+}
+
+/// Options shared by Concat and Batch describing how the stack is laid out
+#[derive(Args)]
+struct ConcatOptions {
+    #[arg(long)]
+    filename_template: String,
+    #[arg(long, required_unless_present = "emit_dimension_sizes_from_files")]
+    size_z: Option<usize>,
+    #[arg(long, default_value_t = 1.0)]
+    physical_size_z: f64,
+    #[arg(long, default_value = "µm")]
+    physical_size_z_unit: LengthUnit,
+    /// Emit one TiffData per file (IFD 0, PlaneCount of the file) instead of one per plane
+    #[arg(long)]
+    emit_tiff_data_planecount_total: bool,
+    /// The files are interleaved RGB(A): their SizeC samples share each IFD, so a single
+    /// channel with SamplesPerPixel=SizeC is emitted with one plane per z
+    #[arg(long)]
+    interleaved: bool,
+    /// Emit one TiffData per file as --emit-tiff-data-planecount-total does, plus a Plane
+    /// for each plane of the file so the plane-to-file mapping stays explicit
+    #[arg(long)]
+    group_tiff_data_by_file: bool,
+    /// Derive SizeZ from the number of files matching the template, and SizeX,
+    /// SizeY and Type from the first of them, instead of the source XML
+    #[arg(long)]
+    emit_dimension_sizes_from_files: bool,
+    /// Emit a Plane for every plane with its absolute PositionZ
+    #[arg(long)]
+    emit_plane_position_from_stage: bool,
+    /// Stage position of the first slice, in the PhysicalSizeZ unit
+    #[arg(long, default_value_t = 0.0)]
+    stage_position_z: f64,
+    /// Map planes to the full-resolution IFDs of FILE's layout, for pyramids
+    /// that store reduced resolutions in the main IFD chain
+    #[arg(long)]
+    base_resolution_ifds: bool,
+    /// Leave a plane, given as z,c,t (e.g. 3,0,0), out of the TiffData; repeatable
+    #[arg(long, visible_alias = "exclude")]
+    exclude_planes: Vec<Selection>,
+    /// CSV of per-plane DeltaT, ExposureTime and PositionZ (in the PhysicalSizeZ unit)
+    /// for the emitted Planes, with columns z,c,t,deltaT,exposure,posZ
+    #[arg(long)]
+    plane_metadata_csv: Option<String>,
+    #[command(flatten)]
+    image: ImageSelector,
+}
+
+/// Compares strings with runs of digits ordered by their numeric value, so `z2` < `z10`
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let a_end = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+            let b_end = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+            let (a_digits, b_digits) = (
+                a[..a_end].trim_start_matches('0'),
+                b[..b_end].trim_start_matches('0'),
+            );
+            let ordering = a_digits
+                .len()
+                .cmp(&b_digits.len())
+                .then_with(|| a_digits.cmp(b_digits));
+            if ordering.is_ne() {
+                return ordering;
+            }
+            (a, b) = (&a[a_end..], &b[b_end..]);
+        } else {
+            if x != y {
+                return x.cmp(&y);
+            }
+            (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+        }
+    }
+}
+
+/// Stably reorders the TiffData by `key`: "ifd", "zct" (FirstZ, FirstC, FirstT)
+/// or "filename", with TiffData of the file itself first
+fn sort_tiff_data(pixels: &mut Pixels, key: &str) {
+    match key {
+        "ifd" => pixels.tiff_data.sort_by_key(|t| t.ifd.unwrap_or(0)),
+        "zct" => pixels.tiff_data.sort_by_key(|t| {
+            (
+                t.first_z.unwrap_or(0),
+                t.first_c.unwrap_or(0),
+                t.first_t.unwrap_or(0),
+            )
+        }),
+        "filename" => pixels.tiff_data.sort_by(|a, b| match (&a.uuid, &b.uuid) {
+            (Some(a), Some(b)) => natural_cmp(&a.file_name, &b.file_name),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        }),
+        _ => unreachable!("validated by clap"),
+    }
+}
+
+/// Expected sizes of an image, e.g. `z=10,c=2,t=1`, axes left out being unchecked
+#[derive(Debug, Clone, Default)]
+struct Shape {
+    x: Option<usize>,
+    y: Option<usize>,
+    z: Option<usize>,
+    c: Option<usize>,
+    t: Option<usize>,
+}
+
+impl std::str::FromStr for Shape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut shape = Shape::default();
+        for pair in s.split(',') {
+            let (axis, size) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected axis=size, got \"{}\"", pair))?;
+            let size = size
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| format!("invalid size for {}: {}", axis, e))?;
+            let slot = match axis.trim().to_ascii_lowercase().as_str() {
+                "x" => &mut shape.x,
+                "y" => &mut shape.y,
+                "z" => &mut shape.z,
+                "c" => &mut shape.c,
+                "t" => &mut shape.t,
+                _ => {
+                    return Err(format!(
+                        "unknown axis \"{}\", expected x, y, z, c or t",
+                        axis
+                    ))
+                }
+            };
+            if slot.replace(size).is_some() {
+                return Err(format!("axis {} is given twice", axis));
+            }
+        }
+        Ok(shape)
+    }
+}
+
+impl Shape {
+    /// Fails listing the expected and actual sizes if any axis differs
+    fn check(&self, pixels: &Pixels) -> anyhow::Result<()> {
+        let axes = [
+            ("x", self.x, pixels.size_x),
+            ("y", self.y, pixels.size_y),
+            ("z", self.z, pixels.size_z),
+            ("c", self.c, pixels.size_c),
+            ("t", self.t, pixels.size_t),
+        ];
+        let mismatches: Vec<String> = axes
+            .iter()
+            .filter_map(|(axis, expected, actual)| {
+                expected
+                    .filter(|expected| expected != actual)
+                    .map(|expected| format!("{}={} (expected {})", axis, actual, expected))
+            })
+            .collect();
+        anyhow::ensure!(
+            mismatches.is_empty(),
+            "{} has shape {}",
+            pixels.id,
+            mismatches.join(", ")
+        );
+        Ok(())
+    }
+}
+
+/// An xsd:dateTime, either with a UTC offset (or `Z`) or without one
+enum AcquisitionDate {
+    Offset(chrono::DateTime<chrono::FixedOffset>),
+    Local(chrono::NaiveDateTime),
+}
+
+impl std::str::FromStr for AcquisitionDate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(date) = chrono::DateTime::parse_from_rfc3339(s) {
+            return Ok(AcquisitionDate::Offset(date));
+        }
+        chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+            .map(AcquisitionDate::Local)
+            .map_err(|_| format!("\"{}\" is not an ISO 8601 date and time", s))
+    }
+}
+
+/// Parses a document UUID, as `urn:uuid:` followed by the 8-4-4-4-12 hex digits,
+/// the prefix being added if missing
+fn parse_uuid(s: &str) -> Result<String, String> {
+    let hex = s.strip_prefix("urn:uuid:").unwrap_or(s);
+    let groups: Vec<&str> = hex.split('-').collect();
+    let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+    if lengths != [8, 4, 4, 4, 12] || !hex.chars().all(|c| c == '-' || c.is_ascii_hexdigit()) {
+        return Err(format!(
+            "expected a UUID like urn:uuid:123e4567-e89b-12d3-a456-426614174000, got \"{}\"",
+            s
+        ));
+    }
+    Ok(format!("urn:uuid:{}", hex))
+}
+
+/// Parses a UTC offset such as `+02:00`, `-0500` or `Z`
+fn parse_utc_offset(s: &str) -> Result<chrono::FixedOffset, String> {
+    if s == "Z" || s.eq_ignore_ascii_case("UTC") {
+        return Ok(chrono::FixedOffset::east_opt(0).unwrap());
+    }
+    let (sign, rest) = match s.split_at_checked(1) {
+        Some(("+", rest)) => (1, rest),
+        Some(("-", rest)) => (-1, rest),
+        _ => return Err(format!("expected a UTC offset like +02:00, got \"{}\"", s)),
+    };
+    let digits: String = rest.chars().filter(|c| *c != ':').collect();
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.parse::<i32>().ok(), Some(0)),
+        4 => (
+            digits[..2].parse::<i32>().ok(),
+            digits[2..].parse::<i32>().ok(),
+        ),
+        _ => (None, None),
+    };
+    match (hours, minutes) {
+        (Some(hours), Some(minutes)) if minutes < 60 => {
+            chrono::FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+                .ok_or_else(|| format!("UTC offset \"{}\" is out of range", s))
+        }
+        _ => Err(format!("expected a UTC offset like +02:00, got \"{}\"", s)),
+    }
+}
+
+/// Rewrites the AcquisitionDate in UTC, dates without an offset being taken in `timezone`
+/// Dates that can't be parsed, or have no offset and no timezone, are left as they are
+fn normalize_acquisition_date(
+    image: &mut Image,
+    timezone: Option<chrono::FixedOffset>,
+    warnings: &Warnings,
+) {
+    let Some(date) = &image.acquisition_date else {
+        return;
+    };
+    let date = match (date.parse::<AcquisitionDate>(), timezone) {
+        (Ok(AcquisitionDate::Offset(date)), _) => date,
+        (Ok(AcquisitionDate::Local(date)), Some(timezone)) => {
+            match date.and_local_timezone(timezone).single() {
+                Some(date) => date,
+                None => return,
+            }
+        }
+        (Ok(AcquisitionDate::Local(_)), None) => {
+            warnings.warn(
+                "acquisition-date-without-offset",
+                Some(&image.id),
+                format!(
+                    "{} AcquisitionDate \"{}\" has no UTC offset, pass --timezone to normalize it",
+                    image.id, date
+                ),
+            );
+            return;
+        }
+        (Err(e), _) => {
+            warnings.warn(
+                "acquisition-date-invalid",
+                Some(&image.id),
+                format!("{} AcquisitionDate {}", image.id, e),
+            );
+            return;
+        }
+    };
+    let utc = date.with_timezone(&chrono::Utc);
+    image.acquisition_date = Some(utc.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true));
+}
+
+/// Selects the image a command operates on, by index or by ID
+#[derive(Args, Clone)]
+struct ImageSelector {
+    /// Index of the image to operate on
+    #[arg(long, default_value_t = 0)]
+    image_index: usize,
+    /// ID of the image to operate on (e.g. Image:1), instead of --image-index
+    #[arg(long, conflicts_with = "image_index")]
+    only_image: Option<String>,
+}
+
+impl ImageSelector {
+    /// Returns the index of the selected image, erroring if there is no such image
+    fn resolve(&self, ome: &OME) -> anyhow::Result<usize> {
+        let ids: Vec<&str> = ome.images.iter().map(|image| image.id.as_str()).collect();
+        match &self.only_image {
+            Some(id) => ids.iter().position(|i| i == id).ok_or_else(|| {
+                anyhow::anyhow!("No Image with ID {} (available: {})", id, ids.join(", "))
+            }),
+            None if self.image_index < ids.len() => Ok(self.image_index),
+            None => Err(anyhow::anyhow!(
+                "No Image at index {} (found {} images)",
+                self.image_index,
+                ids.len()
+            )),
+        }
+    }
+}
+
+/// Transforms applied to the OME before it is written
+#[derive(Args)]
+struct Transforms {
+    /// Fails instead of warning when the OME schema version isn't supported
+    #[arg(long, global = true)]
+    strict: bool,
+    /// Prints each warning to stderr as a JSON object, one per line, with a stable
+    /// `code`, its `severity`, the `id` of the element concerned (if any) and the
+    /// `message`; `validate` prints its issues to its report the same way
+    #[arg(long, global = true)]
+    warnings_json: bool,
+    /// Fails unless every image has these sizes as parsed, e.g. z=10,c=2,t=1
+    #[arg(long, global = true)]
+    assert_shape: Option<Shape>,
+    /// Rewrites the metadata to the canonical XYZCT dimension order
+    #[arg(long, global = true)]
+    normalize_dimension_order: bool,
+    /// Truncates or pads the channels so their number matches SizeC
+    #[arg(long, global = true)]
+    trim_channels_to_sizec: bool,
+    /// Sets the pixel type from the BitsPerSample and SampleFormat of the TIFF
+    #[arg(long, global = true)]
+    pixel_type_from_tiff: bool,
+    /// Fills a SizeX or SizeY of 0 (or missing) from the first referenced TIFF
+    #[arg(long, global = true)]
+    fill_dimensions: bool,
+    /// Copies PhysicalSizeX/Y/Z and their units from the images of a reference OME-TIFF
+    /// or companion, matched by index (a single reference image applies to all)
+    #[arg(long, global = true)]
+    physical_sizes_from: Option<String>,
+    /// Copies the channel Names, Colors and wavelengths from the images of a reference
+    /// OME-TIFF or companion, matched by index (a single reference image applies to all)
+    #[arg(long, global = true)]
+    merge_channels_from: Option<String>,
+    /// Rewrites AcquisitionDate in UTC
+    #[arg(long, global = true)]
+    normalize_dates: bool,
+    /// UTC offset (e.g. +02:00) of AcquisitionDates that have none, for --normalize-dates
+    #[arg(
+        long,
+        global = true,
+        requires = "normalize_dates",
+        allow_hyphen_values = true,
+        value_parser = parse_utc_offset
+    )]
+    timezone: Option<chrono::FixedOffset>,
+    /// Sets the UUID of the OME root, which is otherwise kept from the input; the
+    /// UUIDs of the TiffData, identifying the files they point to, are unchanged
+    #[arg(long, global = true, value_parser = parse_uuid)]
+    uuid: Option<String>,
+    /// Gives Images, Pixels and Channels whose ID is already used a new unique one
+    #[arg(long, global = true)]
+    renumber_ids: bool,
+    /// Sets the SamplesPerPixel of every channel (e.g. 3 for RGB)
+    #[arg(long, global = true)]
+    channel_samples: Option<usize>,
+    /// Reorders the TiffData by IFD, by (Z, C, T) or by file name (natural order)
+    #[arg(long, global = true, value_parser = ["ifd", "zct", "filename"])]
+    sort_tiffdata_by: Option<String>,
+    /// Sets the ExcitationWavelength of each channel, in channel order (e.g. 488,561)
+    #[arg(long, global = true, value_delimiter = ',')]
+    excitation_wavelength: Vec<f64>,
+    /// Sets the EmissionWavelength of each channel, in channel order (e.g. 520,600)
+    #[arg(long, global = true, value_delimiter = ',')]
+    emission_wavelength: Vec<f64>,
+    /// Unit of the wavelengths given by --excitation-wavelength and --emission-wavelength
+    #[arg(long, global = true, default_value = "nm")]
+    wavelength_unit: LengthUnit,
+    /// Embeds the planes of the TIFF as BinData, replacing the TiffData
+    #[arg(long, global = true)]
+    embed_bin_data: bool,
+    /// Byte order of the embedded BinData, defaults to that of this machine
+    #[arg(long, global = true, requires = "embed_bin_data", value_parser = ["big", "little"])]
+    emit_tiff_endianness: Option<String>,
+    #[command(flatten)]
+    grid: StageGrid,
+}
+
+/// A regular grid of tiles, one image per tile filled row by row
+#[derive(Args)]
+struct StageGrid {
+    /// Sets a StageLabel on every image, and the PositionX/Y of its planes, from its tile
+    #[arg(
+        long,
+        global = true,
+        requires_all = ["grid_rows", "grid_cols", "tile_pitch_x"]
+    )]
+    emit_stage_positions_grid: bool,
+    #[arg(long, global = true)]
+    grid_rows: Option<usize>,
+    #[arg(long, global = true)]
+    grid_cols: Option<usize>,
+    /// Distance between the origins of horizontally adjacent tiles
+    #[arg(long, global = true)]
+    tile_pitch_x: Option<f64>,
+    /// Distance between the origins of vertically adjacent tiles, defaults to --tile-pitch-x
+    #[arg(long, global = true)]
+    tile_pitch_y: Option<f64>,
+    #[arg(long, global = true, default_value = "µm")]
+    tile_pitch_unit: LengthUnit,
+}
+
+impl StageGrid {
+    /// Positions the image at `index` at its tile, the first tile being at the origin
+    fn apply(&self, index: usize, image: &mut Image) {
+        let (Some(cols), Some(pitch_x)) = (self.grid_cols, self.tile_pitch_x) else {
+            return;
+        };
+        let pitch_y = self.tile_pitch_y.unwrap_or(pitch_x);
+        let (row, col) = (index / cols, index % cols);
+        let (x, y) = (col as f64 * pitch_x, row as f64 * pitch_y);
+        let unit = Some(self.tile_pitch_unit);
+        image.stage_label = Some(StageLabel {
+            name: format!("r{}c{}", row, col),
+            x: Some(x),
+            x_unit: unit,
+            y: Some(y),
+            y_unit: unit,
+            z: None,
+            z_unit: None,
+        });
+        let pixels = &mut image.pixels;
+        if pixels.planes.is_empty() {
+            pixels.planes = selections_in_order(pixels)
+                .into_iter()
+                .map(Plane::new)
+                .collect();
+        }
+        for plane in pixels.planes.iter_mut() {
+            plane.position_x = Some(x);
+            plane.position_x_unit = unit;
+            plane.position_y = Some(y);
+            plane.position_y_unit = unit;
+        }
+    }
+}
+
+impl Transforms {
+    fn is_empty(&self) -> bool {
+        !(self.strict
+            || self.assert_shape.is_some()
+            || self.normalize_dimension_order
+            || self.trim_channels_to_sizec
+            || self.pixel_type_from_tiff
+            || self.fill_dimensions
+            || self.physical_sizes_from.is_some()
+            || self.uuid.is_some()
+            || self.renumber_ids
+            || self.merge_channels_from.is_some()
+            || self.normalize_dates
+            || self.channel_samples.is_some()
+            || self.sort_tiffdata_by.is_some()
+            || !self.excitation_wavelength.is_empty()
+            || !self.emission_wavelength.is_empty()
+            || self.embed_bin_data
+            || self.grid.emit_stage_positions_grid)
+    }
+
+    /// Applies the transforms to every image, `tiff` being the file the OME was read from
+    /// and `scan_limit` the number of IFDs probed for the OME-XML of referenced TIFFs
+    fn apply(&self, ome: &mut OME, tiff: Option<&str>, scan_limit: usize) -> anyhow::Result<()> {
+        let warnings = Warnings {
+            json: self.warnings_json,
+        };
+        check_schema_version(ome, self.strict, &warnings)?;
+        drop_dangling_annotation_refs(ome, &warnings);
+        if let Some(uuid) = &self.uuid {
+            ome.uuid = Some(uuid.clone());
+        }
+        if self.renumber_ids {
+            renumber_duplicate_ids(ome, &warnings);
+        }
+        let reference: Option<OME> = match &self.physical_sizes_from {
+            Some(file) => Some(from_str(&read_ome_xml(file, scan_limit)?)?),
+            None => None,
+        };
+        let channel_reference: Option<OME> = match &self.merge_channels_from {
+            Some(file) => Some(from_str(&read_ome_xml(file, scan_limit)?)?),
+            None => None,
+        };
+        if self.grid.emit_stage_positions_grid {
+            let tiles = self.grid.grid_rows.unwrap_or(0) * self.grid.grid_cols.unwrap_or(0);
+            anyhow::ensure!(
+                tiles >= ome.images.len(),
+                "A grid of {} tiles can't hold {} images",
+                tiles,
+                ome.images.len()
+            );
+        }
+        for (i, image) in ome.images.iter_mut().enumerate() {
+            if let Some(shape) = &self.assert_shape {
+                shape.check(&image.pixels)?;
+            }
+            if let Some(reference) = &reference {
+                let source = reference_image(reference, i, "physical size")?;
+                copy_physical_sizes(&source.pixels, &mut image.pixels)?;
+            }
+            if self.fill_dimensions {
+                let file = tiff
+                    .ok_or_else(|| anyhow::anyhow!("--fill-dimensions requires an input file"))?;
+                fill_dimensions(file, &mut image.pixels, &warnings)?;
+            }
+            if self.pixel_type_from_tiff {
+                let file = tiff.ok_or_else(|| {
+                    anyhow::anyhow!("--pixel-type-from-tiff requires a TIFF input")
+                })?;
+                infer_pixel_type(file, &mut image.pixels, &warnings)?;
+            }
+            if self.trim_channels_to_sizec {
+                trim_channels_to_size_c(i, &mut image.pixels, &warnings);
+            }
+            if let Some(samples) = self.channel_samples {
+                set_channel_samples(i, &mut image.pixels, samples, &warnings)?;
+            }
+            if let Some(reference) = &channel_reference {
+                let source = reference_image(reference, i, "channel")?;
+                merge_channels(&source.pixels, &mut image.pixels)?;
+            }
+            if self.normalize_dimension_order {
+                normalize_dimension_order(&mut image.pixels, tiff);
+            }
+            if let Some(key) = &self.sort_tiffdata_by {
+                sort_tiff_data(&mut image.pixels, key);
+            }
+            set_wavelengths(
+                &image.id,
+                &mut image.pixels,
+                &self.excitation_wavelength,
+                &self.emission_wavelength,
+                self.wavelength_unit,
+            )?;
+            if self.embed_bin_data {
+                let file =
+                    tiff.ok_or_else(|| anyhow::anyhow!("--embed-bin-data requires a TIFF input"))?;
+                let big_endian = match self.emit_tiff_endianness.as_deref() {
+                    Some(endianness) => endianness == "big",
+                    None => cfg!(target_endian = "big"),
+                };
+                embed_bin_data(file, &mut image.pixels, big_endian)?;
+            }
+            if self.grid.emit_stage_positions_grid {
+                self.grid.apply(i, image);
+            }
+            if self.normalize_dates {
+                normalize_acquisition_date(image, self.timezone, &warnings);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+#[command(propagate_version = true)]
+struct Cli {
+    #[arg(required = false)]
+    file: Option<String>,
+    /// Write the output to a file instead of stdout, replacing it only once the command succeeds
+    #[arg(long, short, global = true)]
+    output: Option<String>,
+    /// Gzip-compress the output, appending `.gz` to the --output path
+    #[arg(long, global = true)]
+    gzip: bool,
+    /// Synthesize the OME from ImageJ hyperstack metadata instead of reading OME-XML
+    #[arg(long, global = true)]
+    from_imagej: bool,
+    /// Only pretty-print the XML of FILE (an OME-TIFF or a companion) as it is, without
+    /// parsing it into the model, so nothing the tool doesn't model is lost
+    #[arg(long, conflicts_with = "from_imagej")]
+    reencode_pretty: bool,
+    /// Encoding of the emitted XML, for readers that don't support UTF-8
+    #[arg(long, global = true, default_value = "UTF-8", value_parser = ["UTF-8", "ISO-8859-1"])]
+    output_encoding: String,
+    /// Number of IFDs to probe for the OME-XML when the first IFD's ImageDescription
+    /// has none (e.g. writers that store it on the last plane)
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 64,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    tail_ifd_scan_limit: usize,
+    #[command(flatten)]
+    transforms: Transforms,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Adds files to myapp
+    Concat {
+        // Positional arguments specific to the Concat subcommand
+        #[arg(required = true)]
+        file: String,
+        /// Also write a master OME-TIFF with the companion metadata embedded, carrying a
+        /// copy of the pixels of FILE that its planes point at, while the other TiffData
+        /// reference the stack files by UUID
+        #[arg(long)]
+        master: Option<String>,
+        #[command(flatten)]
+        options: ConcatOptions,
+    },
+    /// Runs Concat on every file matching a glob, writing each companion next to its input
+    Batch {
+        /// Glob of input files (e.g. 'data/*/stack_z0.ome.tif')
+        #[arg(required = true)]
+        pattern: String,
+        #[command(flatten)]
+        options: ConcatOptions,
+    },
+    /// Creates a minimal OME-XML from dimensions, without an input file
+    New {
+        #[arg(long)]
+        size_x: usize,
+        #[arg(long)]
+        size_y: usize,
+        #[arg(long, default_value_t = 1)]
+        size_z: usize,
+        #[arg(long, default_value_t = 1)]
+        size_c: usize,
+        #[arg(long, default_value_t = 1)]
+        size_t: usize,
+        #[arg(long, default_value = "uint8", value_parser = PIXEL_TYPES)]
+        pixel_type: String,
+        #[arg(long, default_value = "XYZCT")]
+        dimension_order: DimensionOrder,
+    },
+    /// Writes a copy of a TIFF with the OME-XML (after transforms) as its ImageDescription
+    WriteBack {
+        #[arg(required = true)]
+        file: String,
+        /// Path of the new OME-TIFF
+        #[arg(required = true)]
+        target: String,
+        /// Copy the Resolution, Software and DateTime tags of the original IFDs
+        #[arg(long)]
+        copy_tags: bool,
+    },
+    /// Prints a Bio-Formats `.fake` file name with the dimensions of the first image
+    Fake {
+        #[arg(required = true)]
+        file: String,
+        /// Name preceding the encoded dimensions
+        #[arg(long, default_value = "test")]
+        name: String,
+        #[command(flatten)]
+        image: ImageSelector,
+    },
+    /// Writes each plane of an image as a single-plane TIFF next to FILE and prints the
+    /// companion OME-XML of that image referencing them
+    Split {
+        #[arg(required = true)]
+        file: String,
+        /// Name of the plane files, with `{z}`, `{c}` and `{t}` in place of the
+        /// 1-based plane indices (e.g. `plane_z{z}_c{c}.tif`)
+        #[arg(long, required = true)]
+        filename_template: String,
+        #[command(flatten)]
+        image: ImageSelector,
+    },
+    /// Checks that the files referenced by the TiffData exist
+    CheckFiles {
+        #[arg(required = true)]
+        file: String,
+        /// Zip archive to look in for files that aren't on disk
+        #[arg(long)]
+        archive: Option<String>,
+    },
+    /// Prints the ImageDescription bytes exactly as stored, before any parsing
+    DumpRawDescription {
+        #[arg(required = true)]
+        file: String,
+        /// Index of the IFD to read the ImageDescription from
+        #[arg(long, default_value_t = 0)]
+        ifd: usize,
+        /// Print a hex dump instead of the raw bytes
+        #[arg(long)]
+        hex: bool,
+    },
+    /// Prints a summary of the images and their channels
+    Info {
+        #[arg(required = true)]
+        file: String,
+        /// Maximum number of channels listed per image
+        #[arg(long, default_value_t = 10)]
+        max_list: usize,
+        /// Also write the summary as JSON to this file, listing every channel
+        #[arg(long)]
+        json_sidecar: Option<String>,
+        /// Print the shape, dtype and scale of each image as a Python dict literal
+        /// instead of the summary
+        #[arg(long)]
+        numpy: bool,
+    },
+    /// Runs quality checks on the OME-XML, exiting nonzero if any fail
+    Validate {
+        #[arg(required = true)]
+        file: String,
+        /// Warn about axes without a PhysicalSize
+        #[arg(long)]
+        report_missing_physical_sizes: bool,
+        /// Fail if any axis is missing a PhysicalSize
+        #[arg(long)]
+        require_physical_sizes: bool,
+        /// Check the TiffData mapping against the PageNumber tags of the IFDs
+        #[arg(long)]
+        check_page_numbers: bool,
+        /// Fail unless the TiffData reference every plane exactly once
+        #[arg(long)]
+        require_tiffdata_coverage: bool,
+        /// Check that every referenced file has as many IFDs as its TiffData declare planes
+        #[arg(long)]
+        verify_plane_count_per_file: bool,
+        /// A plane, given as z,c,t, that is intentionally absent from the TiffData; repeatable
+        #[arg(
+            long,
+            visible_alias = "exclude",
+            requires = "require_tiffdata_coverage"
+        )]
+        exclude_planes: Vec<Selection>,
+        /// Fail for IDs not following the schema's LSID patterns (e.g. `Channel:0:0`)
+        #[arg(long)]
+        canonical_ids: bool,
+        /// Zip archive to read the referenced files from when they aren't on disk
+        #[arg(long)]
+        archive: Option<String>,
+        /// Companion of FILE, a master OME-TIFF, whose UUIDs must link back to it
+        #[arg(long)]
+        companion: Option<String>,
+        /// Also write the issues as JSON to this file
+        #[arg(long)]
+        json_sidecar: Option<String>,
+    },
+}
+
+/// Tag number of ImageDescription, for reading it from the raw IFDs
+const IMAGE_DESCRIPTION: u16 = 270;
+
+/// Reads the ImageDescription of the first IFD, keeping only the OME-XML if it follows
+/// a caption (e.g. `Acquired on scope 2\0<?xml ...?><OME ...>...</OME>`)
+/// The decoder validates the image layout (compression, samples) before giving access
+/// to any tag, and stops strings at the first NUL, so in either case the tag is read
+/// again from the raw IFD
+/// If the first IFD has no OME-XML, the next IFDs are probed, up to `scan_limit` in total
+fn get_image_description(file: &str, scan_limit: usize) -> anyhow::Result<String> {
+    let reader = std::fs::File::open(file).map(std::io::BufReader::new)?;
+    let description = match tiff::decoder::Decoder::new(reader) {
+        Ok(mut decoder) => match decoder.find_tag(tiff::tags::Tag::ImageDescription)? {
+            Some(tiff::decoder::ifd::Value::Ascii(s)) => Some(s),
+            _ => None,
+        },
+        Err(tiff::TiffError::UnsupportedError(_)) | Err(tiff::TiffError::FormatError(_)) => None,
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(s) = description.filter(|s| s.contains("<OME")) {
+        return Ok(extract_ome_block(&s).to_string());
+    }
+    let reader = std::fs::File::open(file).map(std::io::BufReader::new)?;
+    let mut raw = RawTiff::new(reader)?;
+    for offset in raw.ifd_offsets_within(scan_limit)? {
+        let Some(bytes) = raw.find_tag_bytes(offset, IMAGE_DESCRIPTION)? else {
+            continue;
+        };
+        if let Ok(s) = String::from_utf8(bytes) {
+            if s.contains("<OME") {
+                return Ok(extract_ome_block(&s).to_string());
+            }
+        }
+    }
+    anyhow::bail!(
+        "No OME-XML found in the ImageDescription of the first {} IFDs of {} (see --tail-ifd-scan-limit)",
+        scan_limit,
+        file
+    )
+}
+
+/// The `<OME ...>...</OME>` block of a description, with its XML declaration if any,
+/// or the whole description (minus NULs around it) if it has no such block
+fn extract_ome_block(description: &str) -> &str {
+    let description = description.trim_matches(char::from(0));
+    let Some(root) = description.find("<OME") else {
+        return description;
+    };
+    let start = description[..root].rfind("<?xml").unwrap_or(root);
+    let end = description
+        .rfind("</OME>")
+        .map_or(description.len(), |end| end + "</OME>".len());
+    &description[start..end]
+}
+
+/// An entry of an IFD, with the value/offset field left undecoded
+struct RawEntry {
+    tag: u16,
+    field_type: u16,
+    count: u64,
+    value: [u8; 8],
+}
+
+/// Minimal reader of the IFD structure of classic and BigTIFF files, independent
+/// of the tiff crate's decoder so tag bytes can be read exactly as stored, even
+/// from files whose pixels (or strings) the decoder rejects
+struct RawTiff<R> {
+    reader: R,
+    little_endian: bool,
+    big_tiff: bool,
+    first_ifd: u64,
+}
+
+impl<R: std::io::Read + std::io::Seek> RawTiff<R> {
+    fn new(mut reader: R) -> anyhow::Result<RawTiff<R>> {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+        let little_endian = match &header[..2] {
+            b"II" => true,
+            b"MM" => false,
+            _ => anyhow::bail!("Not a TIFF file"),
+        };
+        let mut raw = RawTiff {
+            reader,
+            little_endian,
+            big_tiff: false,
+            first_ifd: 0,
+        };
+        let magic = raw.u16_from(&header[2..4]);
+        raw.big_tiff = match magic {
+            42 => false,
+            43 => true,
+            _ => anyhow::bail!("Not a TIFF file (version {})", magic),
+        };
+        if raw.big_tiff {
+            // Bytesize of offsets (always 8) and a reserved zero
+            let mut skip = [0u8; 4];
+            raw.reader.read_exact(&mut skip)?;
+        }
+        raw.first_ifd = raw.read_offset()?;
+        Ok(raw)
+    }
+
+    fn u16_from(&self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    }
+
+    fn u64_from(&self, bytes: &[u8]) -> u64 {
+        let mut padded = [0u8; 8];
+        if self.little_endian {
+            padded[..bytes.len()].copy_from_slice(bytes);
+            u64::from_le_bytes(padded)
+        } else {
+            padded[8 - bytes.len()..].copy_from_slice(bytes);
+            u64::from_be_bytes(padded)
+        }
+    }
+
+    fn read_bytes(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = vec![0u8; n];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Reads an offset or count, 4 bytes in classic TIFF and 8 in BigTIFF
+    fn read_offset(&mut self) -> anyhow::Result<u64> {
+        let bytes = self.read_bytes(if self.big_tiff { 8 } else { 4 })?;
+        Ok(self.u64_from(&bytes))
+    }
+
+    /// Reads the entries of the IFD at `offset` and the offset of the next IFD (0 if last)
+    fn read_ifd(&mut self, offset: u64) -> anyhow::Result<(Vec<RawEntry>, u64)> {
+        self.reader.seek(std::io::SeekFrom::Start(offset))?;
+        let count = if self.big_tiff {
+            self.read_offset()?
+        } else {
+            let bytes = self.read_bytes(2)?;
+            u64::from(self.u16_from(&bytes))
+        };
+        let value_len = if self.big_tiff { 8 } else { 4 };
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let bytes = self.read_bytes(4)?;
+            let tag = self.u16_from(&bytes[..2]);
+            let field_type = self.u16_from(&bytes[2..]);
+            let count = self.read_offset()?;
+            let mut value = [0u8; 8];
+            self.reader.read_exact(&mut value[..value_len])?;
+            entries.push(RawEntry {
+                tag,
+                field_type,
+                count,
+                value,
+            });
+        }
+        let next = self.read_offset()?;
+        Ok((entries, next))
+    }
+
+    /// Returns the offsets of every IFD in the main chain
+    fn ifd_offsets(&mut self) -> anyhow::Result<Vec<u64>> {
+        self.ifd_offsets_within(usize::MAX)
+    }
+
+    /// Returns the offsets of the first `limit` IFDs in the main chain
+    fn ifd_offsets_within(&mut self, limit: usize) -> anyhow::Result<Vec<u64>> {
+        let mut offsets = Vec::new();
+        let mut offset = self.first_ifd;
+        while offset != 0 && offsets.len() < limit {
+            anyhow::ensure!(!offsets.contains(&offset), "IFD chain loops at {}", offset);
+            offsets.push(offset);
+            offset = self.read_ifd(offset)?.1;
+        }
+        Ok(offsets)
+    }
+
+    /// Returns the bytes of the entry's value as stored, without decoding them
+    fn entry_bytes(&mut self, entry: &RawEntry) -> anyhow::Result<Vec<u8>> {
+        let size: u64 = match entry.field_type {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            5 | 10 | 12 | 16 | 17 | 18 => 8,
+            field_type => anyhow::bail!("Unknown field type {} of tag {}", field_type, entry.tag),
+        };
+        let len = usize::try_from(size * entry.count)?;
+        let value_len = if self.big_tiff { 8 } else { 4 };
+        if len <= value_len {
+            Ok(entry.value[..len].to_vec())
+        } else {
+            let offset = self.u64_from(&entry.value[..value_len]);
+            self.reader.seek(std::io::SeekFrom::Start(offset))?;
+            self.read_bytes(len)
+        }
+    }
+
+    /// Returns the bytes of `tag` in the IFD at `offset`, if present
+    fn find_tag_bytes(&mut self, offset: u64, tag: u16) -> anyhow::Result<Option<Vec<u8>>> {
+        let (entries, _) = self.read_ifd(offset)?;
+        match entries.iter().find(|entry| entry.tag == tag) {
+            Some(entry) => Ok(Some(self.entry_bytes(entry)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Returns the indices of the full-resolution IFDs in the main chain, skipping
+/// reduced-resolution images (NewSubfileType bit 0) that some pyramid writers
+/// interleave with the base planes
+/// Pyramids stored in SubIFDs are unaffected, as TiffData only index the main chain
+fn full_resolution_ifds(file: &str) -> anyhow::Result<Vec<usize>> {
+    const NEW_SUBFILE_TYPE: u16 = 254;
+    let reader = std::fs::File::open(file).map(std::io::BufReader::new)?;
+    let mut raw = RawTiff::new(reader)?;
+    let mut ifds = Vec::new();
+    for (i, offset) in raw.ifd_offsets()?.into_iter().enumerate() {
+        let subfile_type = match raw.find_tag_bytes(offset, NEW_SUBFILE_TYPE)? {
+            Some(bytes) => raw.u64_from(&bytes),
+            None => 0,
+        };
+        if subfile_type & 1 == 0 {
+            ifds.push(i);
+        }
+    }
+    Ok(ifds)
+}
+
+/// Returns the ImageDescription of an IFD exactly as stored, including the NUL terminator
+fn get_raw_image_description(file: &str, ifd: usize) -> anyhow::Result<Vec<u8>> {
+    let reader = std::fs::File::open(file).map(std::io::BufReader::new)?;
+    let mut raw = RawTiff::new(reader)?;
+    let offsets = raw.ifd_offsets()?;
+    let offset = *offsets
+        .get(ifd)
+        .ok_or_else(|| anyhow::anyhow!("{} has only {} IFDs", file, offsets.len()))?;
+    raw.find_tag_bytes(offset, IMAGE_DESCRIPTION)?
+        .ok_or_else(|| anyhow::anyhow!("No ImageDescription tag found in IFD {}", ifd))
+}
+
+/// Writes `bytes` as a hex dump: offset, 16 hex bytes, and their printable ASCII
+fn write_hex_dump<W: Write>(w: &mut W, bytes: &[u8]) -> std::io::Result<()> {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(w, "{:08x}  {:<47}  {}", i * 16, hex.join(" "), ascii)?;
+    }
+    Ok(())
+}
+
+/// An ASCII tag value that may hold UTF-8, as OME-XML commonly does (e.g. `µm`)
+/// The tiff crate only accepts 7-bit strings, so the bytes are written verbatim
+struct Utf8Ascii<'a>(&'a str);
+
+impl tiff::encoder::TiffValue for Utf8Ascii<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: tiff::tags::Type = tiff::tags::Type::ASCII;
+
+    fn count(&self) -> usize {
+        self.0.len() + 1
+    }
+
+    fn data(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = self.0.as_bytes().to_vec();
+        bytes.push(0);
+        std::borrow::Cow::Owned(bytes)
+    }
+}
+
+/// The decoded pixels of a single IFD
+struct Page {
+    width: u32,
+    height: u32,
+    color_type: tiff::ColorType,
+    pixels: tiff::decoder::DecodingResult,
+}
+
+impl Page {
+    fn read<R: std::io::Read + std::io::Seek>(
+        decoder: &mut tiff::decoder::Decoder<R>,
+    ) -> anyhow::Result<Page> {
+        let (width, height) = decoder.dimensions()?;
+        Ok(Page {
+            width,
+            height,
+            color_type: decoder.colortype()?,
+            pixels: decoder.read_image()?,
+        })
+    }
+}
+
+/// Tags other than the pixel layout written alongside a page
+#[derive(Default)]
+struct PageTags {
+    image_description: Option<String>,
+    software: Option<String>,
+    date_time: Option<String>,
+    x_resolution: Option<(u32, u32)>,
+    y_resolution: Option<(u32, u32)>,
+    resolution_unit: Option<u16>,
+}
+
+impl PageTags {
+    /// Reads the curated set of tags carried over by `--copy-tags` from the current IFD
+    fn copy_from<R: std::io::Read + std::io::Seek>(
+        decoder: &mut tiff::decoder::Decoder<R>,
+    ) -> anyhow::Result<PageTags> {
+        use tiff::decoder::ifd::Value;
+        use tiff::tags::Tag;
+        let mut ascii = |tag| -> anyhow::Result<Option<String>> {
+            match decoder.find_tag(tag)? {
+                Some(Value::Ascii(s)) => Ok(Some(s)),
+                _ => Ok(None),
+            }
+        };
+        let software = ascii(Tag::Software)?;
+        let date_time = ascii(Tag::DateTime)?;
+        let mut rational = |tag| -> anyhow::Result<Option<(u32, u32)>> {
+            match decoder.find_tag(tag)? {
+                Some(Value::Rational(n, d)) => Ok(Some((n, d))),
+                _ => Ok(None),
+            }
+        };
+        let x_resolution = rational(Tag::XResolution)?;
+        let y_resolution = rational(Tag::YResolution)?;
+        Ok(PageTags {
+            image_description: None,
+            software,
+            date_time,
+            x_resolution,
+            y_resolution,
+            resolution_unit: decoder.find_tag_unsigned(Tag::ResolutionUnit)?,
+        })
+    }
+
+    fn write<W: std::io::Write + std::io::Seek, K: tiff::encoder::TiffKind>(
+        &self,
+        encoder: &mut tiff::encoder::DirectoryEncoder<W, K>,
+    ) -> anyhow::Result<()> {
+        use tiff::encoder::Rational;
+        use tiff::tags::Tag;
+        if let Some(s) = &self.image_description {
+            encoder.write_tag(Tag::ImageDescription, Utf8Ascii(s))?;
+        }
+        if let Some(s) = &self.software {
+            encoder.write_tag(Tag::Software, Utf8Ascii(s))?;
+        }
+        if let Some(s) = &self.date_time {
+            encoder.write_tag(Tag::DateTime, Utf8Ascii(s))?;
+        }
+        if let Some((n, d)) = self.x_resolution {
+            encoder.write_tag(Tag::XResolution, Rational { n, d })?;
+        }
+        if let Some((n, d)) = self.y_resolution {
+            encoder.write_tag(Tag::YResolution, Rational { n, d })?;
+        }
+        if let Some(unit) = self.resolution_unit {
+            encoder.write_tag(Tag::ResolutionUnit, unit)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_page_as<C, W>(
+    encoder: &mut tiff::encoder::TiffEncoder<W>,
+    page: &Page,
+    data: &[C::Inner],
+    tags: &PageTags,
+) -> anyhow::Result<()>
+where
+    C: tiff::encoder::colortype::ColorType,
+    [C::Inner]: tiff::encoder::TiffValue,
+    W: std::io::Write + std::io::Seek,
+{
+    let mut image = encoder.new_image::<C>(page.width, page.height)?;
+    tags.write(image.encoder())?;
+    image.write_data(data)?;
+    Ok(())
+}
+
+/// Writes the page uncompressed as the next IFD of `encoder`
+fn write_page<W: std::io::Write + std::io::Seek>(
+    encoder: &mut tiff::encoder::TiffEncoder<W>,
+    page: &Page,
+    tags: &PageTags,
+) -> anyhow::Result<()> {
+    use tiff::decoder::DecodingResult as D;
+    use tiff::encoder::colortype::*;
+    use tiff::ColorType as C;
+    match (&page.pixels, page.color_type) {
+        (D::U8(data), C::Gray(_)) => write_page_as::<Gray8, _>(encoder, page, data, tags),
+        (D::U16(data), C::Gray(_)) => write_page_as::<Gray16, _>(encoder, page, data, tags),
+        (D::U32(data), C::Gray(_)) => write_page_as::<Gray32, _>(encoder, page, data, tags),
+        (D::U64(data), C::Gray(_)) => write_page_as::<Gray64, _>(encoder, page, data, tags),
+        (D::I8(data), C::Gray(_)) => write_page_as::<GrayI8, _>(encoder, page, data, tags),
+        (D::I16(data), C::Gray(_)) => write_page_as::<GrayI16, _>(encoder, page, data, tags),
+        (D::I32(data), C::Gray(_)) => write_page_as::<GrayI32, _>(encoder, page, data, tags),
+        (D::I64(data), C::Gray(_)) => write_page_as::<GrayI64, _>(encoder, page, data, tags),
+        (D::F32(data), C::Gray(_)) => write_page_as::<Gray32Float, _>(encoder, page, data, tags),
+        (D::F64(data), C::Gray(_)) => write_page_as::<Gray64Float, _>(encoder, page, data, tags),
+        (D::U8(data), C::RGB(_)) => write_page_as::<RGB8, _>(encoder, page, data, tags),
+        (D::U16(data), C::RGB(_)) => write_page_as::<RGB16, _>(encoder, page, data, tags),
+        (D::U32(data), C::RGB(_)) => write_page_as::<RGB32, _>(encoder, page, data, tags),
+        (D::F32(data), C::RGB(_)) => write_page_as::<RGB32Float, _>(encoder, page, data, tags),
+        (D::U8(data), C::RGBA(_)) => write_page_as::<RGBA8, _>(encoder, page, data, tags),
+        (D::U16(data), C::RGBA(_)) => write_page_as::<RGBA16, _>(encoder, page, data, tags),
+        (_, color_type) => Err(anyhow::anyhow!("Unsupported pixel layout {:?}", color_type)),
+    }
+}
+
+/// Copies every IFD of `file` to `output`, replacing the ImageDescription
+/// of the first IFD with `xml_str`
+fn write_back(file: &str, output: &str, xml_str: &str, copy_tags: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        std::fs::canonicalize(file).ok() != std::fs::canonicalize(output).ok(),
+        "Refusing to overwrite the input file {}",
+        file
+    );
+    let reader = std::fs::File::open(file).map(std::io::BufReader::new)?;
+    let mut decoder = tiff::decoder::Decoder::new(reader)?;
+    let writer = std::fs::File::create(output).map(std::io::BufWriter::new)?;
+    let mut encoder = tiff::encoder::TiffEncoder::new(writer)?;
+    let mut first = true;
+    loop {
+        let mut tags = if copy_tags {
+            PageTags::copy_from(&mut decoder)?
+        } else {
+            PageTags::default()
+        };
+        if first {
+            tags.image_description = Some(xml_str.to_string());
+            first = false;
+        }
+        let page = Page::read(&mut decoder)?;
+        write_page(&mut encoder, &page, &tags)?;
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+    Ok(())
+}
+
+/// Infers the OME pixel type from the BitsPerSample and SampleFormat of the current IFD
+fn pixel_type_from_tiff<R: std::io::Read + std::io::Seek>(
+    decoder: &mut tiff::decoder::Decoder<R>,
+) -> anyhow::Result<String> {
+    let bits = match decoder.colortype()? {
+        tiff::ColorType::Gray(bits)
+        | tiff::ColorType::RGB(bits)
+        | tiff::ColorType::Palette(bits)
+        | tiff::ColorType::GrayA(bits)
+        | tiff::ColorType::RGBA(bits)
+        | tiff::ColorType::CMYK(bits)
+        | tiff::ColorType::YCbCr(bits) => bits,
+    };
+    // 1 = unsigned integer (the default), 2 = signed integer, 3 = IEEE float
+    let sample_format = decoder
+        .find_tag_unsigned_vec::<u16>(tiff::tags::Tag::SampleFormat)?
+        .and_then(|formats| formats.first().copied())
+        .unwrap_or(1);
+    let pixel_type = match (sample_format, bits) {
+        (1, 1) => "bit",
+        (1, 8) => "uint8",
+        (1, 16) => "uint16",
+        (1, 32) => "uint32",
+        (2, 8) => "int8",
+        (2, 16) => "int16",
+        (2, 32) => "int32",
+        (3, 32) => "float",
+        (3, 64) => "double",
+        _ => anyhow::bail!(
+            "No OME pixel type for {}-bit samples with SampleFormat {}",
+            bits,
+            sample_format
+        ),
+    };
+    Ok(pixel_type.to_string())
+}
+
+/// The samples of a decoded page as bytes in the given byte order
+fn sample_bytes(pixels: &tiff::decoder::DecodingResult, big_endian: bool) -> Vec<u8> {
+    use tiff::decoder::DecodingResult as D;
+    macro_rules! to_bytes {
+        ($data:expr) => {
+            $data
+                .iter()
+                .flat_map(|v| {
+                    if big_endian {
+                        v.to_be_bytes()
+                    } else {
+                        v.to_le_bytes()
+                    }
+                })
+                .collect()
+        };
+    }
+    match pixels {
+        D::U8(data) => data.clone(),
+        D::I8(data) => to_bytes!(data),
+        D::U16(data) => to_bytes!(data),
+        D::I16(data) => to_bytes!(data),
+        D::U32(data) => to_bytes!(data),
+        D::I32(data) => to_bytes!(data),
+        D::U64(data) => to_bytes!(data),
+        D::I64(data) => to_bytes!(data),
+        D::F32(data) => to_bytes!(data),
+        D::F64(data) => to_bytes!(data),
+    }
+}
+
+/// Replaces the TiffData of the image by one BinData per plane, in the dimension order,
+/// reading each plane from the IFD (and file) its TiffData points to
+fn embed_bin_data(file: &str, pixels: &mut Pixels, big_endian: bool) -> anyhow::Result<()> {
+    let mut planes = tiff_data_planes(pixels, Some(file));
+    planes.sort_by_key(|p| get_relative_ifd_index(p.selection, pixels));
+    let expected = pixels.plane_count();
+    anyhow::ensure!(
+        planes.len() == expected,
+        "{} has {} planes but its TiffData reference {}",
+        pixels.id,
+        expected,
+        planes.len()
+    );
+    let mut bin_data = Vec::with_capacity(planes.len());
+    for plane in planes {
+        let tiff = plane_file(file, &plane);
+        let reader = std::fs::File::open(&tiff).map(std::io::BufReader::new)?;
+        let mut decoder = tiff::decoder::Decoder::new(reader)?;
+        decoder.seek_to_image(plane.ifd)?;
+        let bytes = sample_bytes(&decoder.read_image()?, big_endian);
+        bin_data.push(BinData::encode(&bytes, big_endian));
+    }
+    pixels.bin_data = bin_data;
+    pixels.tiff_data.clear();
+    Ok(())
+}
+
+/// Returns the file name of a plane, `{z}`, `{c}` and `{t}` in the template being
+/// replaced by the 1-based index zero-padded to the digits of the size, as in `concat`
+fn plane_filename(template: &str, selection: Selection, pixels: &Pixels) -> String {
+    let pad = |index: usize, size: usize| {
+        format!("{:0width$}", index + 1, width = size.to_string().len())
+    };
+    template
+        .replace("{z}", &pad(selection.z, pixels.size_z))
+        .replace("{c}", &pad(selection.c, pixels.effective_size_c()))
+        .replace("{t}", &pad(selection.t, pixels.size_t))
+}
+
+/// Writes each plane of the image as a single-plane TIFF next to `file`, named by the
+/// template, and points the TiffData at them, one per plane
+fn split_planes(file: &str, pixels: &mut Pixels, template: &str) -> anyhow::Result<()> {
+    for (placeholder, size) in [
+        ("{z}", pixels.size_z),
+        ("{c}", pixels.effective_size_c()),
+        ("{t}", pixels.size_t),
+    ] {
+        anyhow::ensure!(
+            size == 1 || template.contains(placeholder),
+            "The filename template needs {} for {} planes along it",
+            placeholder,
+            size
+        );
+    }
+    let path = std::path::Path::new(file);
+    let mut planes = tiff_data_planes(pixels, Some(file));
+    planes.sort_by_key(|p| get_relative_ifd_index(p.selection, pixels));
+    let expected = pixels.plane_count();
+    anyhow::ensure!(
+        planes.len() == expected,
+        "{} has {} planes but its TiffData reference {}",
+        pixels.id,
+        expected,
+        planes.len()
+    );
+    let mut tiff_data = Vec::with_capacity(planes.len());
+    for plane in planes {
+        let tiff = plane_file(file, &plane);
+        let name = plane_filename(template, plane.selection, pixels);
+        let output = path.with_file_name(&name);
+        anyhow::ensure!(
+            std::fs::canonicalize(&tiff).ok() != std::fs::canonicalize(&output).ok(),
+            "Refusing to overwrite the input file {}",
+            tiff.display()
+        );
+        let reader = std::fs::File::open(&tiff).map(std::io::BufReader::new)?;
+        let mut decoder = tiff::decoder::Decoder::new(reader)?;
+        decoder.seek_to_image(plane.ifd)?;
+        let page = Page::read(&mut decoder)?;
+        let writer = std::fs::File::create(&output).map(std::io::BufWriter::new)?;
+        let mut encoder = tiff::encoder::TiffEncoder::new(writer)?;
+        write_page(&mut encoder, &page, &PageTags::default())?;
+        tiff_data.push(TiffData {
+            ifd: Some(0),
+            plane_count: Some(1),
+            first_c: Some(plane.selection.c),
+            first_z: Some(plane.selection.z),
+            first_t: Some(plane.selection.t),
+            uuid: Some(Uuid {
+                file_name: name,
+                value: None,
+            }),
+        });
+    }
+    pixels.tiff_data = tiff_data;
+    Ok(())
+}
+
+/// Sets the pixel type from the IFD holding the first plane of the image
+/// Planes stored in other files are skipped in favor of the first IFD
+fn infer_pixel_type(file: &str, pixels: &mut Pixels, warnings: &Warnings) -> anyhow::Result<()> {
+    let ifd = pixels
+        .tiff_data
+        .first()
+        .filter(|tiff_data| tiff_data.uuid.is_none())
+        .and_then(|tiff_data| tiff_data.ifd)
+        .unwrap_or(0);
+    let reader = std::fs::File::open(file).map(std::io::BufReader::new)?;
+    let mut decoder = tiff::decoder::Decoder::new(reader)?;
+    decoder.seek_to_image(ifd)?;
+    let pixel_type = pixel_type_from_tiff(&mut decoder)?;
+    if pixels.r#type != pixel_type {
+        warnings.warn(
+            "pixel-type-replaced",
+            Some(&pixels.id),
+            format!(
+                "{} Type \"{}\" replaced by \"{}\" from the TIFF",
+                pixels.id, pixels.r#type, pixel_type
+            ),
+        );
+        pixels.r#type = pixel_type;
+    }
+    Ok(())
+}
+
+/// Synthesizes an OME from an ImageJ hyperstack, whose dimensions are stored
+/// as `key=value` lines in the ImageDescription (e.g. `ImageJ=1.53t\nimages=6\nchannels=2\n...`)
+/// ImageJ always stores planes in XYCZT order
+fn imagej_to_ome(file: &str) -> anyhow::Result<OME> {
+    let reader = std::fs::File::open(file).map(std::io::BufReader::new)?;
+    let mut decoder = tiff::decoder::Decoder::new(reader)?;
+    let description = match decoder.find_tag(tiff::tags::Tag::ImageDescription)? {
+        Some(tiff::decoder::ifd::Value::Ascii(s)) if s.starts_with("ImageJ=") => s,
+        _ => anyhow::bail!("No ImageJ metadata found in {}", file),
+    };
+    let value = |key: &str| {
+        description.lines().find_map(|line| {
+            line.split_once('=')
+                .filter(|(k, _)| k.trim() == key)
+                .map(|(_, v)| v.trim().to_string())
+        })
+    };
+    let count = |key: &str| -> anyhow::Result<Option<usize>> {
+        value(key)
+            .map(|v| v.parse::<usize>())
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("Invalid ImageJ {}: {}", key, e))
+    };
+    let images = count("images")?;
+    let size_c = count("channels")?.unwrap_or(1);
+    let size_t = count("frames")?.unwrap_or(1);
+    // A plain stack only records the number of images, which are slices
+    let size_z = match count("slices")? {
+        Some(slices) => slices,
+        None => images.map_or(1, |n| n / (size_c * size_t)),
+    };
+    if let Some(images) = images {
+        anyhow::ensure!(
+            images == size_z * size_c * size_t,
+            "ImageJ images={} does not match channels={} x slices={} x frames={}",
+            images,
+            size_c,
+            size_z,
+            size_t
+        );
+    }
+
+    let (size_x, size_y) = decoder.dimensions()?;
+    let mut ome = to_minimal_ome(&ImageConfig {
+        size_x: size_x as usize,
+        size_y: size_y as usize,
+        size_z,
+        size_c,
+        size_t,
+        pixel_type: pixel_type_from_tiff(&mut decoder)?,
+        dimension_order: DimensionOrder::XYCZT,
+    });
+
+    let unit = match value("unit").as_deref() {
+        None | Some("pixel") => None,
+        Some(unit) => Some(
+            unit.replace("\\u00B5", "µ")
+                .parse::<LengthUnit>()
+                .map_err(|e| anyhow::anyhow!("Invalid ImageJ unit: {}", e))?,
+        ),
+    };
+    if let Some(unit) = unit {
+        let pixels = &mut ome.images[0].pixels;
+        // Resolution is stored as pixels per unit
+        let mut physical_size = |tag| -> anyhow::Result<Option<f64>> {
+            match decoder.find_tag(tag)? {
+                Some(tiff::decoder::ifd::Value::Rational(n, d)) if n != 0 => {
+                    Ok(Some(d as f64 / n as f64))
+                }
+                _ => Ok(None),
+            }
+        };
+        pixels.physical_size_x = physical_size(tiff::tags::Tag::XResolution)?;
+        pixels.physical_size_x_unit = pixels.physical_size_x.map(|_| unit);
+        pixels.physical_size_y = physical_size(tiff::tags::Tag::YResolution)?;
+        pixels.physical_size_y_unit = pixels.physical_size_y.map(|_| unit);
+        pixels.physical_size_z = value("spacing").and_then(|v| v.parse().ok());
+        pixels.physical_size_z_unit = pixels.physical_size_z.map(|_| unit);
+    }
+    Ok(ome)
+}
+
+/// Returns the OME-XML of a TIFF, synthesizing it from ImageJ metadata if `from_imagej`
+fn get_source_xml(file: &str, from_imagej: bool, scan_limit: usize) -> anyhow::Result<String> {
+    if from_imagej {
+        Ok(to_string(&imagej_to_ome(file)?)?)
+    } else {
+        get_image_description(file, scan_limit)
+    }
+}
+
+/// Reads the OME-XML from a TIFF's ImageDescription, or directly
+/// from the file if it is a companion (.ome or .xml)
+fn read_ome_xml(file: &str, scan_limit: usize) -> anyhow::Result<String> {
+    let path = std::path::Path::new(file);
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("ome") | Some("xml") => Ok(std::fs::read_to_string(path)?),
+        _ => get_image_description(file, scan_limit),
+    }
+}
+
+/// Returns the unique file names referenced by the TiffData, in order of appearance
+fn referenced_files(ome: &OME) -> Vec<String> {
+    let mut files: Vec<String> = Vec::new();
+    for image in &ome.images {
+        for tiff_data in &image.pixels.tiff_data {
+            if let Some(uuid) = &tiff_data.uuid {
+                if !files.contains(&uuid.file_name) {
+                    files.push(uuid.file_name.clone());
+                }
+            }
+        }
+    }
+    files
+}
+
+enum FileLocation {
+    Disk,
+    Archive,
+    Missing,
+}
+
+/// Names of the entries of a zip archive
+fn archive_entries(archive: &str) -> anyhow::Result<Vec<String>> {
+    let reader = std::fs::File::open(archive).map(std::io::BufReader::new)?;
+    let zip = zip::ZipArchive::new(reader)?;
+    let names = zip.file_names().map(String::from).collect();
+    Ok(names)
+}
+
+/// The entry holding the file `name`, at the root of the archive or in a directory of it
+fn in_archive<'a>(entries: &'a [String], name: &str) -> Option<&'a str> {
+    entries
+        .iter()
+        .find(|entry| *entry == name || entry.ends_with(&format!("/{}", name)))
+        .map(String::as_str)
+}
+
+/// A file that can be decoded as a TIFF, from disk or from an archive
+trait ReadSeek: std::io::Read + std::io::Seek {}
+
+impl<T: std::io::Read + std::io::Seek> ReadSeek for T {}
+
+/// Opens a file referenced by the OME at `file`, reading it from `archive` if it isn't
+/// on disk
+fn open_referenced(
+    file: &str,
+    tiff: &std::path::Path,
+    archive: Option<&str>,
+) -> anyhow::Result<Box<dyn ReadSeek>> {
+    let archive = match archive {
+        Some(archive) if !tiff.exists() => archive,
+        _ => {
+            return Ok(Box::new(
+                std::fs::File::open(tiff).map(std::io::BufReader::new)?,
+            ))
+        }
+    };
+    let dir = std::path::Path::new(file)
+        .parent()
+        .unwrap_or(std::path::Path::new(""));
+    let name = tiff.strip_prefix(dir).unwrap_or(tiff).to_string_lossy();
+    let entries = archive_entries(archive)?;
+    let entry = in_archive(&entries, &name).ok_or_else(|| {
+        anyhow::anyhow!("{} is neither on disk nor in {}", tiff.display(), archive)
+    })?;
+    let reader = std::fs::File::open(archive).map(std::io::BufReader::new)?;
+    let mut zip = zip::ZipArchive::new(reader)?;
+    let mut bytes = Vec::new();
+    std::io::Read::read_to_end(&mut zip.by_name(entry)?, &mut bytes)?;
+    Ok(Box::new(std::io::Cursor::new(bytes)))
+}
+
+/// Locates each referenced file relative to the directory of `file`,
+/// falling back to the entries of `archive` for files that aren't on disk
+fn check_files(
+    file: &str,
+    ome: &OME,
+    archive: Option<&str>,
+) -> anyhow::Result<Vec<(String, FileLocation)>> {
+    let dir = std::path::Path::new(file)
+        .parent()
+        .unwrap_or(std::path::Path::new(""));
+    let entries = match archive {
+        Some(archive) => archive_entries(archive)?,
+        None => Vec::new(),
+    };
+    Ok(referenced_files(ome)
+        .into_iter()
+        .map(|name| {
+            let location = if dir.join(&name).exists() {
+                FileLocation::Disk
+            } else if in_archive(&entries, &name).is_some() {
+                FileLocation::Archive
+            } else {
+                FileLocation::Missing
+            };
+            (name, location)
+        })
+        .collect())
+}
+
+/// Formats a physical size and its unit, or `-` if missing
+fn format_physical_size(size: Option<f64>, unit: &Option<LengthUnit>) -> String {
+    match (size, unit) {
+        (Some(size), Some(unit)) => format!("{} {}", size, unit),
+        (Some(size), None) => size.to_string(),
+        (None, _) => "-".to_string(),
+    }
+}
+
+/// Writes a human-readable summary of every image, listing at most `max_list` channels each
+fn write_info<W: Write>(w: &mut W, ome: &OME, max_list: usize) -> std::io::Result<()> {
+    writeln!(w, "Schema: {}", schema_version(ome).unwrap_or("-"))?;
+    for image in &ome.images {
+        let pixels = &image.pixels;
+        match &image.name {
+            Some(name) => writeln!(w, "{} \"{}\"", image.id, name)?,
+            None => writeln!(w, "{}", image.id)?,
+        }
+        writeln!(w, "  Type: {}", pixels.r#type)?;
+        writeln!(
+            w,
+            "  Dimensions: X={} Y={} Z={} C={} T={} ({})",
+            pixels.size_x,
+            pixels.size_y,
+            pixels.size_z,
+            pixels.size_c,
+            pixels.size_t,
+            pixels.dimension_order
+        )?;
+        writeln!(
+            w,
+            "  PhysicalSize: X={} Y={} Z={}",
+            format_physical_size(pixels.physical_size_x, &pixels.physical_size_x_unit),
+            format_physical_size(pixels.physical_size_y, &pixels.physical_size_y_unit),
+            format_physical_size(pixels.physical_size_z, &pixels.physical_size_z_unit),
+        )?;
+        let planes = pixels.size_z * pixels.size_c * pixels.size_t;
+        match plane_bytes(pixels) {
+            Some(n) => writeln!(w, "  Bytes: {} per plane, {} total", n, n * planes)?,
+            None => writeln!(w, "  Bytes: -")?,
+        }
+        writeln!(w, "  Channels: {}", pixels.channels.len())?;
+        for (c, channel) in pixels.channels.iter().take(max_list).enumerate() {
+            match &channel.name {
+                Some(name) => writeln!(w, "    {}: {} ({})", c, name, channel.id)?,
+                None => writeln!(w, "    {}: {}", c, channel.id)?,
+            }
+        }
+        if pixels.channels.len() > max_list {
+            writeln!(w, "    … and {} more", pixels.channels.len() - max_list)?;
+        }
+        writeln!(w, "  TiffData: {}", pixels.tiff_data.len())?;
+    }
+    Ok(())
+}
+
+/// Writes one Python literal per image (readable with `ast.literal_eval`) with the
+/// TCZYX shape, numpy dtype and scale of the pixels, the scale of T and C being 1
+fn write_numpy_info<W: Write>(w: &mut W, ome: &OME) -> anyhow::Result<()> {
+    for image in &ome.images {
+        let pixels = &image.pixels;
+        let dtype =
+            numpy_dtype(&pixels.r#type).map_err(|e| anyhow::anyhow!("{}: {}", pixels.id, e))?;
+        writeln!(
+            w,
+            "{{'shape': ({}, {}, {}, {}, {}), 'dtype': '{}', 'scale': (1.0, 1.0, {}, {}, {})}}",
+            pixels.size_t,
+            pixels.size_c,
+            pixels.size_z,
+            pixels.size_y,
+            pixels.size_x,
+            dtype,
+            python_float(pixels.physical_size_z.unwrap_or(1.0)),
+            python_float(pixels.physical_size_y.unwrap_or(1.0)),
+            python_float(pixels.physical_size_x.unwrap_or(1.0)),
+        )?;
+    }
+    Ok(())
+}
+
+/// A Python literal for the float, `float('nan')` and `float('inf')` for those Rust
+/// prints as `NaN` and `inf`
+fn python_float(value: f64) -> String {
+    match value {
+        v if v.is_nan() => "float('nan')".to_string(),
+        v if v.is_infinite() => format!("{}float('inf')", if v < 0.0 { "-" } else { "" }),
+        v => format!("{:?}", v),
+    }
+}
+
+/// The summary printed by `write_info` as JSON, for `--json-sidecar`
+fn info_json(ome: &OME) -> serde_json::Value {
+    let physical_size = |size: Option<f64>, unit: &Option<LengthUnit>| {
+        size.map(|size| serde_json::json!({ "value": size, "unit": unit.map(|u| u.to_string()) }))
+    };
+    let images: Vec<serde_json::Value> = ome
+        .images
+        .iter()
+        .map(|image| {
+            let pixels = &image.pixels;
+            let channels: Vec<serde_json::Value> = pixels
+                .channels
+                .iter()
+                .map(|channel| serde_json::json!({ "id": channel.id, "name": channel.name }))
+                .collect();
+            serde_json::json!({
+                "id": image.id,
+                "name": image.name,
+                "type": pixels.r#type,
+                "size": {
+                    "x": pixels.size_x,
+                    "y": pixels.size_y,
+                    "z": pixels.size_z,
+                    "c": pixels.size_c,
+                    "t": pixels.size_t,
+                },
+                "dimension_order": pixels.dimension_order.to_string(),
+                "physical_size": {
+                    "x": physical_size(pixels.physical_size_x, &pixels.physical_size_x_unit),
+                    "y": physical_size(pixels.physical_size_y, &pixels.physical_size_y_unit),
+                    "z": physical_size(pixels.physical_size_z, &pixels.physical_size_z_unit),
+                },
+                "plane_bytes": plane_bytes(pixels),
+                "total_bytes": plane_bytes(pixels)
+                    .map(|n| n * pixels.size_z * pixels.size_c * pixels.size_t),
+                "channels": channels,
+                "tiff_data": pixels.tiff_data.len(),
+            })
+        })
+        .collect();
+    serde_json::json!({ "schema_version": schema_version(ome), "images": images })
+}
+
+/// Writes `value` as pretty-printed JSON to `path`
+fn write_json_sidecar(path: &str, value: &serde_json::Value) -> anyhow::Result<()> {
+    let file = std::fs::File::create(path).map(std::io::BufWriter::new)?;
+    serde_json::to_writer_pretty(file, value)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Warning,
+    Error,
+}
+
+impl Severity {
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// A problem found by `validate`
+#[derive(Serialize)]
+struct Issue {
+    severity: Severity,
+    /// Kind of issue, like the code of a warning
+    code: &'static str,
+    message: String,
+}
+
+/// Checks that a master OME-TIFF and its companion point to each other: the master's
+/// BinaryOnly names the companion and its root UUID, and the companion's TiffData
+/// referencing the master carry the master's root UUID
+fn check_master_companion(
+    master_file: &str,
+    master: &OME,
+    companion_file: &str,
+    companion: &OME,
+) -> Vec<Issue> {
+    let mut errors = Vec::new();
+    let file_name = |file: &str| {
+        std::path::Path::new(file)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    let (master_name, companion_name) = (file_name(master_file), file_name(companion_file));
+    match &master.binary_only {
+        None => errors.push(format!("{} has no BinaryOnly element", master_name)),
+        Some(binary_only) => {
+            if binary_only.metadata_file != companion_name {
+                errors.push(format!(
+                    "{} BinaryOnly MetadataFile is {} instead of {}",
+                    master_name, binary_only.metadata_file, companion_name
+                ));
+            }
+            match &companion.uuid {
+                None => errors.push(format!("{} has no root UUID", companion_name)),
+                Some(uuid) if *uuid != binary_only.uuid => errors.push(format!(
+                    "{} BinaryOnly UUID {} does not match the UUID {} of {}",
+                    master_name, binary_only.uuid, uuid, companion_name
+                )),
+                Some(_) => {}
+            }
+        }
+    }
+    let references = companion
+        .images
+        .iter()
+        .flat_map(|image| &image.pixels.tiff_data)
+        .filter_map(|tiff_data| tiff_data.uuid.as_ref())
+        .filter(|uuid| uuid.file_name == master_name);
+    let mut referenced = false;
+    for reference in references {
+        referenced = true;
+        if reference.value != master.uuid {
+            errors.push(format!(
+                "{} references {} with UUID {} but its UUID is {}",
+                companion_name,
+                master_name,
+                reference.value.as_deref().unwrap_or("-"),
+                master.uuid.as_deref().unwrap_or("-")
+            ));
+            break;
+        }
+    }
+    if !referenced {
+        errors.push(format!(
+            "{} has no TiffData referencing {}",
+            companion_name, master_name
+        ));
+    }
+    errors
+        .into_iter()
+        .map(|message| Issue {
+            severity: Severity::Error,
+            code: "master-companion-mismatch",
+            message,
+        })
+        .collect()
+}
+
+/// Warns about every AcquisitionDate that isn't an ISO 8601 date and time
+fn check_acquisition_dates(ome: &OME) -> Vec<Issue> {
+    ome.images
+        .iter()
+        .filter_map(|image| {
+            let date = image.acquisition_date.as_ref()?;
+            let e = date.parse::<AcquisitionDate>().err()?;
+            Some(Issue {
+                severity: Severity::Warning,
+                code: "acquisition-date-invalid",
+                message: format!("{} AcquisitionDate {}", image.id, e),
+            })
+        })
+        .collect()
+}
+
+/// Lists the planes no TiffData references, and those referenced more than once
+/// Planes in `excluded` are expected to be unreferenced, and are only reported if they are
+/// Images with their pixels in BinData are skipped, and TiffData at an IFD of another
+/// file than `file` without any First* are warned about, as they start at the first plane
+fn check_tiff_data_coverage(file: &str, ome: &OME, excluded: &[Selection]) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for image in &ome.images {
+        let pixels = &image.pixels;
+        if pixels.tiff_data.is_empty() && !pixels.bin_data.is_empty() {
+            continue;
+        }
+        for tiff_data in &pixels.tiff_data {
+            let (Some(ifd @ 1..), Some(uuid)) = (tiff_data.ifd, &tiff_data.uuid) else {
+                continue;
+            };
+            let first = [tiff_data.first_z, tiff_data.first_c, tiff_data.first_t];
+            if first.iter().all(Option::is_none) && !in_own_file(tiff_data, Some(file)) {
+                issues.push(Issue {
+                    severity: Severity::Warning,
+                    code: "first-plane-defaulted",
+                    message: format!(
+                        "{} TiffData at IFD {} of {} has no FirstZ/FirstC/FirstT, so it starts at the first plane",
+                        pixels.id, ifd, uuid.file_name
+                    ),
+                });
+            }
+        }
+        let mut counts = vec![0; pixels.plane_count()];
+        for plane in tiff_data_planes(pixels, Some(file)) {
+            counts[get_relative_ifd_index(plane.selection, pixels)] += 1;
+        }
+        for (index, count) in counts.into_iter().enumerate() {
+            let selection = ifd_to_selection(index, pixels).expect("index within planes");
+            let problem = match (count, excluded.contains(&selection)) {
+                (0, true) | (1, false) => continue,
+                (0, false) => "is not referenced by any TiffData".to_string(),
+                (_, true) => "is excluded but referenced by TiffData".to_string(),
+                (n, false) => format!("is referenced by {} TiffData", n),
+            };
+            let Selection { t, z, c } = selection;
+            issues.push(Issue {
+                severity: Severity::Error,
+                code: "plane-coverage",
+                message: format!("{} plane Z={} C={} T={} {}", pixels.id, z, c, t, problem),
+            });
+        }
+    }
+    issues
+}
+
+/// The IDs of the Images, Pixels and Channels, with the name of their element, in
+/// document order
+fn element_ids(ome: &OME) -> Vec<(&'static str, &str)> {
+    let mut ids = Vec::new();
+    for image in &ome.images {
+        ids.push(("Image", image.id.as_str()));
+        ids.push(("Pixels", image.pixels.id.as_str()));
+        for channel in &image.pixels.channels {
+            ids.push(("Channel", channel.id.as_str()));
+        }
+    }
+    ids
+}
+
+/// Fails for every ID shared by several Images, Pixels or Channels, see `--renumber-ids`
+fn check_duplicate_ids(ome: &OME) -> Vec<Issue> {
+    let ids = element_ids(ome);
+    let mut issues = Vec::new();
+    for (i, (element, id)) in ids.iter().enumerate() {
+        let first = ids.iter().position(|(_, other)| other == id);
+        if first != Some(i) {
+            continue;
+        }
+        let count = ids.iter().filter(|(_, other)| other == id).count();
+        if count > 1 {
+            issues.push(Issue {
+                severity: Severity::Error,
+                code: "duplicate-id",
+                message: format!(
+                    "{} ID \"{}\" is used by {} elements (fix with --renumber-ids)",
+                    element, id, count
+                ),
+            });
+        }
+    }
+    issues
+}
+
+/// Gives every Image, Pixels and Channel whose ID was already used earlier in the
+/// document a new unique ID, the canonical one for its position if free (`Image:1`,
+/// `Channel:1:0`), so the first element keeps its ID and references to it stay valid
+fn renumber_duplicate_ids(ome: &mut OME, warnings: &Warnings) {
+    let mut used: Vec<String> = element_ids(ome)
+        .into_iter()
+        .map(|(_, id)| id.to_string())
+        .collect();
+    let mut seen: Vec<String> = Vec::new();
+    let mut renumber = |id: &mut String, canonical: &dyn Fn(usize) -> String, start: usize| {
+        if !seen.contains(id) {
+            seen.push(id.clone());
+            return;
+        }
+        let new_id = (start..)
+            .map(canonical)
+            .find(|candidate| !used.contains(candidate))
+            .expect("unbounded candidates");
+        warnings.warn(
+            "id-renumbered",
+            Some(id),
+            format!("Duplicate ID \"{}\" renumbered to \"{}\"", id, new_id),
+        );
+        used.push(new_id.clone());
+        seen.push(new_id.clone());
+        *id = new_id;
+    };
+    for (i, image) in ome.images.iter_mut().enumerate() {
+        renumber(&mut image.id, &|n| format!("Image:{}", n), i);
+        renumber(&mut image.pixels.id, &|n| format!("Pixels:{}", n), i);
+        for (c, channel) in image.pixels.channels.iter_mut().enumerate() {
+            renumber(&mut channel.id, &|n| format!("Channel:{}:{}", i, n), c);
+        }
+    }
+}
+
+/// Decodes every BinData, checking it holds exactly one plane of the image
+fn check_bin_data(ome: &OME) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for image in &ome.images {
+        let pixels = &image.pixels;
+        if pixels.bin_data.is_empty() {
+            continue;
+        }
+        let expected = pixels.plane_count();
+        if pixels.bin_data.len() != expected {
+            issues.push(Issue {
+                severity: Severity::Error,
+                code: "bin-data-count",
+                message: format!(
+                    "{} has {} planes but {} BinData",
+                    pixels.id,
+                    expected,
+                    pixels.bin_data.len()
+                ),
+            });
+        }
+        let samples = pixels.size_c / pixels.effective_size_c();
+        let plane_bytes = plane_bytes(pixels).map(|n| n * samples);
+        for (i, bin_data) in pixels.bin_data.iter().enumerate() {
+            let message = match bin_data.decode() {
+                Err(e) => format!("{} BinData {} cannot be decoded: {}", pixels.id, i, e),
+                Ok(bytes) if plane_bytes.is_some_and(|n| n != bytes.len()) => format!(
+                    "{} BinData {} holds {} bytes but a {} plane of {}x{} takes {}",
+                    pixels.id,
+                    i,
+                    bytes.len(),
+                    pixels.r#type,
+                    pixels.size_x,
+                    pixels.size_y,
+                    plane_bytes.unwrap()
+                ),
+                Ok(_) => continue,
+            };
+            issues.push(Issue {
+                severity: Severity::Error,
+                code: "bin-data-invalid",
+                message,
+            });
+        }
+    }
+    issues
+}
+
+/// Lists the axes of each image that have no PhysicalSize, and warns about those whose
+/// PhysicalSize has no unit, which is then read as µm
+fn check_physical_sizes(ome: &OME, severity: Severity) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for image in &ome.images {
+        let pixels = &image.pixels;
+        let sizes = [
+            ("X", pixels.physical_size_x, pixels.physical_size_x_unit),
+            ("Y", pixels.physical_size_y, pixels.physical_size_y_unit),
+            ("Z", pixels.physical_size_z, pixels.physical_size_z_unit),
+        ];
+        for (axis, _, _) in sizes
+            .iter()
+            .filter(|(_, size, unit)| size.is_some() && unit.is_none())
+        {
+            issues.push(Issue {
+                severity: Severity::Warning,
+                code: "unit-missing",
+                message: format!("{} PhysicalSize{} has no unit, read as µm", pixels.id, axis),
+            });
+        }
+        let missing: Vec<&str> = sizes
+            .iter()
+            .filter(|(_, size, _)| size.is_none())
+            .map(|(axis, _, _)| *axis)
+            .collect();
+        if !missing.is_empty() {
+            issues.push(Issue {
+                severity,
+                code: "physical-size-missing",
+                message: format!(
+                    "{} is uncalibrated along {} (no PhysicalSize)",
+                    image.id,
+                    missing.join(", ")
+                ),
+            });
+        }
+    }
+    issues
+}
+
+/// Whether `id` follows the schema's pattern for IDs of `kind` elements: `Kind:` then
+/// anything without whitespace (`Channel:0:0`), optionally as a full LSID
+/// (`urn:lsid:example.org:Channel:0:0`)
+fn is_lsid(id: &str, kind: &str) -> bool {
+    let local = match id.strip_prefix("urn:lsid:") {
+        Some(rest) => match rest.split_once(&format!(":{}:", kind)) {
+            Some((authority, local)) if authority.contains('.') => local,
+            _ => return false,
+        },
+        None => match id
+            .strip_prefix(kind)
+            .and_then(|rest| rest.strip_prefix(':'))
+        {
+            Some(local) => local,
+            None => return false,
+        },
+    };
+    !local.is_empty() && !local.chars().any(char::is_whitespace)
+}
+
+/// Fails for every ID of an Image, Pixels, Channel or annotation (and references to
+/// one) that doesn't follow the schema's ID pattern, which some importers reject
+fn check_canonical_ids(ome: &OME) -> Vec<Issue> {
+    let mut ids: Vec<(&str, &str, &str)> = Vec::new();
+    for image in &ome.images {
+        ids.push(("Image", "Image", &image.id));
+        ids.push(("Pixels", "Pixels", &image.pixels.id));
+        for channel in &image.pixels.channels {
+            ids.push(("Channel", "Channel", &channel.id));
+        }
+        let refs = image
+            .annotation_refs
+            .iter()
+            .chain(&image.pixels.annotation_refs)
+            .chain(
+                image
+                    .pixels
+                    .channels
+                    .iter()
+                    .flat_map(|c| &c.annotation_refs),
+            );
+        for annotation_ref in refs {
+            ids.push(("AnnotationRef", "Annotation", &annotation_ref.id));
+        }
+    }
+    if let Some(structured) = &ome.structured_annotations {
+        for id in structured.annotations.iter().filter_map(Annotation::id) {
+            ids.push(("Annotation", "Annotation", id));
+        }
+    }
+    ids.into_iter()
+        .filter(|(_, kind, id)| !is_lsid(id, kind))
+        .map(|(element, kind, id)| Issue {
+            severity: Severity::Error,
+            code: "non-canonical-id",
+            message: format!(
+                "{} ID \"{}\" doesn't match the pattern {}:... (or urn:lsid:...:{}:...)",
+                element, id, kind, kind
+            ),
+        })
+        .collect()
+}
+
+/// A new random document UUID, as `urn:uuid:...`
+fn new_uuid() -> String {
+    format!("urn:uuid:{}", uuid::Uuid::new_v4())
+}
+
+/// Builds the OME embedded in the master written next to `file` from the companion XML,
+/// following the Bio-Formats multifile layout: the master gets its own root UUID, the
+/// TiffData of `file` point at the master's IFDs (a copy of them), and every other
+/// TiffData carries the root UUID of the file it references, left unset if that file
+/// has none
+fn master_ome(
+    file: &str,
+    master: &str,
+    xml_str: &str,
+    scan_limit: usize,
+    warnings: &Warnings,
+) -> anyhow::Result<OME> {
+    let path = std::path::Path::new(file);
+    let parent = |path: &std::path::Path| {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        std::fs::canonicalize(dir.unwrap_or(std::path::Path::new("."))).ok()
+    };
+    anyhow::ensure!(
+        parent(path) == parent(std::path::Path::new(master)),
+        "The master {} must be written next to {}, which its TiffData are relative to",
+        master,
+        file
+    );
+    let file_name = |path: &str| {
+        std::path::Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    let (source_name, master_name) = (file_name(file), file_name(master));
+    let mut ome: OME = from_str(xml_str)?;
+    let master_uuid = new_uuid();
+    ome.uuid = Some(master_uuid.clone());
+    ome.binary_only = None;
+    let mut root_uuids: Vec<(String, Option<String>)> = Vec::new();
+    for image in ome.images.iter_mut() {
+        for tiff_data in image.pixels.tiff_data.iter_mut() {
+            let Some(uuid) = tiff_data.uuid.as_mut() else {
+                continue;
+            };
+            if uuid.file_name == source_name {
+                uuid.file_name = master_name.clone();
+                uuid.value = Some(master_uuid.clone());
+                continue;
+            }
+            if let Some((_, value)) = root_uuids.iter().find(|(name, _)| *name == uuid.file_name) {
+                uuid.value = value.clone();
+                continue;
+            }
+            let referenced = path.with_file_name(&uuid.file_name);
+            let xml = get_image_description(&referenced.to_string_lossy(), scan_limit)
+                .map_err(|e| anyhow::anyhow!("{}: {}", referenced.display(), e))?;
+            let value = from_str::<OME>(&xml)?.uuid;
+            if value.is_none() {
+                warnings.warn(
+                    "uuid-missing",
+                    None,
+                    format!(
+                        "{} has no root UUID, so the master references it by name only; stamp it with --uuid to link it",
+                        uuid.file_name
+                    ),
+                );
+            }
+            root_uuids.push((uuid.file_name.clone(), value.clone()));
+            uuid.value = value;
+        }
+    }
+    Ok(ome)
+}
+
+/// Where the command output is written, optionally gzip-compressed
+/// A file is written to a temporary file next to it, renamed over it by `finish`, so
+/// a failing command leaves a previous output intact
+struct Output {
+    sink: Sink,
+    /// The temporary file and the path it is renamed to
+    pending: Option<(tempfile::TempPath, std::path::PathBuf)>,
+}
+
+enum Sink {
+    Plain(Box<dyn Write>),
+    Gzip(flate2::write::GzEncoder<Box<dyn Write>>),
+}
+
+impl Output {
+    fn open(path: Option<&str>, gzip: bool) -> anyhow::Result<Output> {
+        let (writer, pending): (Box<dyn Write>, _) = match path {
+            Some(path) => {
+                let path = match gzip && !path.ends_with(".gz") {
+                    true => std::path::PathBuf::from(format!("{}.gz", path)),
+                    false => std::path::PathBuf::from(path),
+                };
+                let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+                let mut builder = tempfile::Builder::new();
+                // The mode File::create gives, rather than the owner-only one of temp files
+                #[cfg(unix)]
+                builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+                let (file, temp) = builder
+                    .tempfile_in(dir.unwrap_or(std::path::Path::new(".")))?
+                    .into_parts();
+                (Box::new(std::io::BufWriter::new(file)), Some((temp, path)))
+            }
+            None => (Box::new(std::io::stdout().lock()), None),
+        };
+        let sink = if gzip {
+            let level = flate2::Compression::default();
+            Sink::Gzip(flate2::write::GzEncoder::new(writer, level))
+        } else {
+            Sink::Plain(writer)
+        };
+        Ok(Output { sink, pending })
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        match self.sink {
+            Sink::Plain(mut writer) => writer.flush()?,
+            Sink::Gzip(encoder) => encoder.finish()?.flush()?,
+        }
+        if let Some((temp, path)) = self.pending {
+            temp.persist(&path)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.sink {
+            Sink::Plain(writer) => writer.write(buf),
+            Sink::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.sink {
+            Sink::Plain(writer) => writer.flush(),
+            Sink::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Compares the number of IFDs of every referenced file with the number of planes
+/// the TiffData declare in it, reporting files that can't be opened as well
+fn check_plane_count_per_file(file: &str, ome: &OME, archive: Option<&str>) -> Vec<Issue> {
+    let mut declared: Vec<(std::path::PathBuf, usize)> = Vec::new();
+    for image in &ome.images {
+        for plane in tiff_data_planes(&image.pixels, Some(file)) {
+            let tiff = plane_file(file, &plane);
+            match declared.iter_mut().find(|(p, _)| *p == tiff) {
+                Some((_, count)) => *count += 1,
+                None => declared.push((tiff, 1)),
+            }
+        }
+    }
+    let mut issues = Vec::new();
+    for (tiff, count) in declared {
+        let ifds =
+            open_referenced(file, &tiff, archive).and_then(|f| RawTiff::new(f)?.ifd_offsets());
+        let (code, message) = match ifds {
+            Ok(ifds) if ifds.len() == count => continue,
+            Ok(ifds) => (
+                "plane-count-mismatch",
+                format!(
+                    "{} has {} IFDs but its TiffData declare {} planes",
+                    tiff.display(),
+                    ifds.len(),
+                    count
+                ),
+            ),
+            Err(e) => (
+                "file-unreadable",
+                format!("{} can't be read: {}", tiff.display(), e),
+            ),
+        };
+        issues.push(Issue {
+            severity: Severity::Error,
+            code,
+            message,
+        });
+    }
+    issues
+}
+
+/// Reads the page number (the first value of the PageNumber tag) of every IFD
+fn page_numbers(reader: Box<dyn ReadSeek>) -> anyhow::Result<Vec<Option<u16>>> {
+    const PAGE_NUMBER: tiff::tags::Tag = tiff::tags::Tag::Unknown(297);
+    let mut decoder = tiff::decoder::Decoder::new(reader)?;
+    let mut pages = Vec::new();
+    loop {
+        let page = decoder
+            .find_tag_unsigned_vec::<u16>(PAGE_NUMBER)?
+            .and_then(|values| values.first().copied());
+        pages.push(page);
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+    Ok(pages)
+}
+
+/// Checks that IFDs carrying a PageNumber tag hold the plane at that position in their
+/// file, the planes of each file being ordered by image, then by the dimension order,
+/// so every file of a multifile stack starts at page 0
+/// Files that can't be read are reported instead of their planes
+fn check_page_numbers(file: &str, ome: &OME, archive: Option<&str>) -> Vec<Issue> {
+    // Each plane with its image, its file and its index in the dimension order
+    let mut planes = Vec::new();
+    for (i, image) in ome.images.iter().enumerate() {
+        for plane in tiff_data_planes(&image.pixels, Some(file)) {
+            let tiff = plane_file(file, &plane);
+            let index = get_relative_ifd_index(plane.selection, &image.pixels);
+            planes.push((i, tiff, index, plane));
+        }
+    }
+    let mut files: Vec<&std::path::PathBuf> = Vec::new();
+    for (_, tiff, _, _) in &planes {
+        if !files.contains(&tiff) {
+            files.push(tiff);
+        }
+    }
+    let mut issues = Vec::new();
+    for tiff in files {
+        let numbers = match open_referenced(file, tiff, archive).and_then(page_numbers) {
+            Ok(numbers) => numbers,
+            Err(e) => {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    code: "file-unreadable",
+                    message: format!("{} can't be read for PageNumber: {}", tiff.display(), e),
+                });
+                continue;
+            }
+        };
+        let mut in_file: Vec<_> = planes.iter().filter(|(_, p, _, _)| p == tiff).collect();
+        in_file.sort_by_key(|(i, _, index, _)| (*i, *index));
+        for (position, (i, _, _, plane)) in in_file.into_iter().enumerate() {
+            let Some(Some(page)) = numbers.get(plane.ifd) else {
+                continue;
+            };
+            if usize::from(*page) != position {
+                let Selection { t, z, c } = plane.selection;
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    code: "page-number-mismatch",
+                    message: format!(
+                        "{} IFD {} has PageNumber {} but holds its plane {} (Z={} C={} T={} of {})",
+                        tiff.display(),
+                        plane.ifd,
+                        page,
+                        position,
+                        z,
+                        c,
+                        t,
+                        ome.images[*i].id
+                    ),
+                });
+            }
+        }
+    }
+    issues
+}
+
+/// Encodes the XML, declaring the encoding when it isn't UTF-8
+/// Characters outside ISO-8859-1 are written as character references
+fn encode_xml(xml: &str, encoding: &str) -> Vec<u8> {
+    if encoding == "UTF-8" {
+        return xml.as_bytes().to_vec();
+    }
+    let body = match xml.strip_prefix("<?xml") {
+        Some(rest) => rest.split_once("?>").map_or(rest, |(_, body)| body),
+        None => xml,
+    };
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"{}\"?>\n{}",
+        encoding,
+        body.trim_start()
+    );
+    let mut bytes = Vec::with_capacity(xml.len());
+    for c in xml.chars() {
+        match u8::try_from(u32::from(c)) {
+            Ok(b) => bytes.push(b),
+            Err(_) => bytes.extend(format!("&#{};", u32::from(c)).as_bytes()),
+        }
+    }
+    bytes
+}
+
+/// Builds the companion OME-XML for the stack starting at `file`
+fn concat(file: &str, options: &ConcatOptions, cli: &Cli) -> anyhow::Result<String> {
+    let xml_str = get_source_xml(file, cli.from_imagej, cli.tail_ifd_scan_limit)?;
+    let warnings = Warnings {
+        json: cli.transforms.warnings_json,
+    };
+    let size_z = match options.size_z {
+        Some(size_z) if !options.emit_dimension_sizes_from_files => size_z,
+        _ => {
+            let size_z = discover_stack_size(file, &options.filename_template)?;
+            warnings.warn(
+                "dimension-inferred",
+                None,
+                format!(
+                    "SizeZ set to {} from the files matching {}",
+                    size_z, options.filename_template
+                ),
+            );
+            size_z
+        }
+    };
+    let config = StackConfig {
+        size_z,
+        physical_size_z: options.physical_size_z,
+        physical_size_z_unit: options.physical_size_z_unit,
+        filename_template: options.filename_template.to_string(),
+        image: options.image.clone(),
+        exclude_planes: options.exclude_planes.clone(),
+        plane_count_total: options.emit_tiff_data_planecount_total
+            || options.group_tiff_data_by_file,
+        emit_planes: options.group_tiff_data_by_file || options.plane_metadata_csv.is_some(),
+        interleaved: options.interleaved,
+        plane_position_z: options
+            .emit_plane_position_from_stage
+            .then_some(options.stage_position_z),
+    };
+    let mut ome = to_multifile_companion_ome(&xml_str, &config, &warnings)?;
+    let index = options.image.resolve(&ome)?;
+    if options.emit_dimension_sizes_from_files {
+        let first = std::path::Path::new(file).with_file_name(config.filename(0)?);
+        dimensions_from_tiff(&first, &mut ome.images[index].pixels)?;
+    }
+    if options.base_resolution_ifds {
+        let ifds = full_resolution_ifds(file)?;
+        for tiff_data in ome.images[index].pixels.tiff_data.iter_mut() {
+            let ifd = tiff_data.ifd.unwrap_or(0);
+            let plane_count = tiff_data.plane_count.unwrap_or(1);
+            let bases = ifds.get(ifd..ifd + plane_count).ok_or_else(|| {
+                anyhow::anyhow!("{} has only {} full-resolution IFDs", file, ifds.len())
+            })?;
+            anyhow::ensure!(
+                bases.windows(2).all(|w| w[1] == w[0] + 1),
+                "Full-resolution IFDs {:?} are not consecutive, use one TiffData per plane",
+                bases
+            );
+            tiff_data.ifd = Some(bases[0]);
+        }
+    }
+    if let Some(path) = &options.plane_metadata_csv {
+        let rows = read_plane_metadata_csv(path)?;
+        apply_plane_metadata(
+            &mut ome.images[index].pixels,
+            &rows,
+            options.physical_size_z_unit,
+        )?;
+    }
+    cli.transforms
+        .apply(&mut ome, Some(file), cli.tail_ifd_scan_limit)?;
+    Ok(to_string(&ome)?)
+}
+
+/// The companion written next to `file` by Batch, `stack.ome.tif` becoming `stack.companion.ome`
+fn companion_path(file: &std::path::Path) -> std::path::PathBuf {
+    let name = file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let stem = [".ome.tiff", ".ome.tif", ".tiff", ".tif"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(&name);
+    file.with_file_name(format!("{}.companion.ome", stem))
+}
+
+fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
-    let stdout = std::io::stdout();
-    let mut handle = stdout.lock();
+    let mut handle = Output::open(cli.output.as_deref(), cli.gzip)?;
+    run(&cli, &mut handle)?;
+    handle.finish()
+}
 
+/// Runs the command, writing its output to `handle`
+fn run<W: Write>(cli: &Cli, handle: &mut W) -> anyhow::Result<()> {
     match &cli.command {
         Some(Commands::Concat {
             file,
+            master,
+            options,
+        }) => {
+            let xml_str = concat(file, options, cli)?;
+            if let Some(master) = master {
+                let warnings = Warnings {
+                    json: cli.transforms.warnings_json,
+                };
+                let ome = master_ome(file, master, &xml_str, cli.tail_ifd_scan_limit, &warnings)?;
+                write_back(file, master, &to_string(&ome)?, false)?;
+            }
+            let doc: xmlem::Document = xml_str.parse()?;
+            handle.write_all(&encode_xml(&doc.to_string_pretty(), &cli.output_encoding))?;
+        }
+        Some(Commands::Batch { pattern, options }) => {
+            let mut failed = 0;
+            let mut total = 0;
+            for entry in glob::glob(pattern)? {
+                total += 1;
+                // Unreadable directory entries are reported like any other failure
+                let path = match entry {
+                    Ok(path) => path,
+                    Err(e) => {
+                        failed += 1;
+                        writeln!(handle, "failed: {}", e)?;
+                        continue;
+                    }
+                };
+                let file = path.to_string_lossy().into_owned();
+                let companion = companion_path(&path);
+                let result = concat(&file, options, cli).and_then(|xml_str| {
+                    let doc: xmlem::Document = xml_str.parse()?;
+                    let bytes = encode_xml(&doc.to_string_pretty(), &cli.output_encoding);
+                    Ok(std::fs::write(&companion, bytes)?)
+                });
+                match result {
+                    Ok(()) => writeln!(handle, "ok: {} -> {}", file, companion.display())?,
+                    Err(e) => {
+                        failed += 1;
+                        writeln!(handle, "failed: {}: {:#}", file, e)?;
+                    }
+                }
+            }
+            if total == 0 {
+                anyhow::bail!("No files match {}", pattern);
+            }
+            if failed > 0 {
+                anyhow::bail!("{} of {} file(s) failed", failed, total);
+            }
+        }
+        Some(Commands::New {
+            size_x,
+            size_y,
             size_z,
-            physical_size_z,
-            physical_size_z_unit,
-            filename_template,
+            size_c,
+            size_t,
+            pixel_type,
+            dimension_order,
         }) => {
-            let xml_str = get_image_description(file)?;
-            let ome = to_multifile_companion_ome(
-                &xml_str,
-                &StackConfig {
-                    size_z: *size_z,
-                    physical_size_z: *physical_size_z,
-                    physical_size_z_unit: physical_size_z_unit.to_string(),
-                    filename_template: filename_template.to_string(),
-                },
+            let mut ome = to_minimal_ome(&ImageConfig {
+                size_x: *size_x,
+                size_y: *size_y,
+                size_z: *size_z,
+                size_c: *size_c,
+                size_t: *size_t,
+                pixel_type: pixel_type.to_string(),
+                dimension_order: *dimension_order,
+            });
+            cli.transforms
+                .apply(&mut ome, None, cli.tail_ifd_scan_limit)?;
+            let doc: xmlem::Document = to_string(&ome)?.parse()?;
+            handle.write_all(&encode_xml(&doc.to_string_pretty(), &cli.output_encoding))?;
+        }
+        Some(Commands::WriteBack {
+            file,
+            target,
+            copy_tags,
+        }) => {
+            let mut xml_str = get_source_xml(file, cli.from_imagej, cli.tail_ifd_scan_limit)?;
+            if !cli.transforms.is_empty() {
+                let mut ome: OME = from_str(&xml_str)?;
+                cli.transforms
+                    .apply(&mut ome, Some(file), cli.tail_ifd_scan_limit)?;
+                xml_str = to_string(&ome)?;
+            }
+            write_back(file, target, &xml_str, *copy_tags)?;
+        }
+        Some(Commands::Fake { file, name, image }) => {
+            let mut ome: OME = from_str(&get_source_xml(
+                file,
+                cli.from_imagej,
+                cli.tail_ifd_scan_limit,
+            )?)?;
+            cli.transforms
+                .apply(&mut ome, Some(file), cli.tail_ifd_scan_limit)?;
+            let index = image.resolve(&ome)?;
+            writeln!(
+                handle,
+                "{}",
+                to_fake_descriptor(name, &ome.images[index].pixels)
             )?;
+        }
+        Some(Commands::Split {
+            file,
+            filename_template,
+            image,
+        }) => {
+            let mut ome: OME = from_str(&get_source_xml(
+                file,
+                cli.from_imagej,
+                cli.tail_ifd_scan_limit,
+            )?)?;
+            cli.transforms
+                .apply(&mut ome, Some(file), cli.tail_ifd_scan_limit)?;
+            let index = image.resolve(&ome)?;
+            let mut image = ome.images.swap_remove(index);
+            split_planes(file, &mut image.pixels, filename_template)?;
+            ome.images = vec![image];
+            ome.binary_only = None;
             let doc: xmlem::Document = to_string(&ome)?.parse()?;
-            handle.write_all(doc.to_string_pretty().as_bytes())?;
+            handle.write_all(&encode_xml(&doc.to_string_pretty(), &cli.output_encoding))?;
+        }
+        Some(Commands::CheckFiles { file, archive }) => {
+            let ome: OME = from_str(&read_ome_xml(file, cli.tail_ifd_scan_limit)?)?;
+            let mut missing = 0;
+            for (name, location) in check_files(file, &ome, archive.as_deref())? {
+                let status = match location {
+                    FileLocation::Disk => "ok",
+                    FileLocation::Archive => "ok (archive)",
+                    FileLocation::Missing => {
+                        missing += 1;
+                        "missing"
+                    }
+                };
+                writeln!(handle, "{}\t{}", status, name)?;
+            }
+            if missing > 0 {
+                anyhow::bail!("{} referenced file(s) not found", missing);
+            }
+        }
+        Some(Commands::DumpRawDescription { file, ifd, hex }) => {
+            let bytes = get_raw_image_description(file, *ifd)?;
+            if *hex {
+                write_hex_dump(handle, &bytes)?;
+            } else {
+                handle.write_all(&bytes)?;
+            }
+        }
+        Some(Commands::Info {
+            file,
+            max_list,
+            json_sidecar,
+            numpy,
+        }) => {
+            let mut ome: OME = from_str(&read_ome_xml(file, cli.tail_ifd_scan_limit)?)?;
+            cli.transforms
+                .apply(&mut ome, Some(file), cli.tail_ifd_scan_limit)?;
+            if *numpy {
+                write_numpy_info(handle, &ome)?;
+            } else {
+                write_info(handle, &ome, *max_list)?;
+            }
+            if let Some(path) = json_sidecar {
+                write_json_sidecar(path, &info_json(&ome))?;
+            }
+        }
+        Some(Commands::Validate {
+            file,
+            report_missing_physical_sizes,
+            require_physical_sizes,
+            check_page_numbers: check_pages,
+            require_tiffdata_coverage,
+            verify_plane_count_per_file,
+            exclude_planes,
+            canonical_ids,
+            companion,
+            archive,
+            json_sidecar,
+        }) => {
+            let mut ome: OME = from_str(&read_ome_xml(file, cli.tail_ifd_scan_limit)?)?;
+            cli.transforms
+                .apply(&mut ome, Some(file), cli.tail_ifd_scan_limit)?;
+            let mut issues = Vec::new();
+            if *require_physical_sizes {
+                issues.extend(check_physical_sizes(&ome, Severity::Error));
+            } else if *report_missing_physical_sizes {
+                issues.extend(check_physical_sizes(&ome, Severity::Warning));
+            }
+            if *check_pages {
+                issues.extend(check_page_numbers(file, &ome, archive.as_deref()));
+            }
+            if *require_tiffdata_coverage {
+                issues.extend(check_tiff_data_coverage(file, &ome, exclude_planes));
+            }
+            if *verify_plane_count_per_file {
+                issues.extend(check_plane_count_per_file(file, &ome, archive.as_deref()));
+            }
+            if *canonical_ids {
+                issues.extend(check_canonical_ids(&ome));
+            }
+            if let Some(companion) = companion {
+                let companion_ome: OME =
+                    from_str(&read_ome_xml(companion, cli.tail_ifd_scan_limit)?)?;
+                issues.extend(check_master_companion(
+                    file,
+                    &ome,
+                    companion,
+                    &companion_ome,
+                ));
+            }
+            issues.extend(check_duplicate_ids(&ome));
+            issues.extend(check_bin_data(&ome));
+            issues.extend(check_acquisition_dates(&ome));
+            let warnings = Warnings {
+                json: cli.transforms.warnings_json,
+            };
+            let mut errors = 0;
+            for issue in &issues {
+                if issue.severity == Severity::Error {
+                    errors += 1;
+                }
+                let line = warnings.line(issue.severity, issue.code, None, &issue.message);
+                writeln!(handle, "{}", line)?;
+            }
+            if let Some(path) = json_sidecar {
+                let report = serde_json::json!({
+                    "file": file,
+                    "issues": issues,
+                    "errors": errors,
+                    "passed": errors == 0,
+                });
+                write_json_sidecar(path, &report)?;
+            }
+            if errors > 0 {
+                anyhow::bail!("{} check(s) failed", errors);
+            }
+        }
+        None if cli.reencode_pretty => {
+            let file = cli
+                .file
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("--reencode-pretty requires a FILE"))?;
+            anyhow::ensure!(
+                cli.transforms.is_empty(),
+                "--reencode-pretty only reformats, transforms need the model-based passthrough"
+            );
+            let doc: xmlem::Document = read_ome_xml(file, cli.tail_ifd_scan_limit)?.parse()?;
+            handle.write_all(&encode_xml(&doc.to_string_pretty(), &cli.output_encoding))?;
         }
         None => {
             if let Some(file) = &cli.file {
-                let xml_str = get_image_description(file)?;
+                let mut xml_str = get_source_xml(file, cli.from_imagej, cli.tail_ifd_scan_limit)?;
+                if !cli.transforms.is_empty() {
+                    let mut ome: OME = from_str(&xml_str)?;
+                    cli.transforms
+                        .apply(&mut ome, Some(file), cli.tail_ifd_scan_limit)?;
+                    xml_str = to_string(&ome)?;
+                }
                 let doc: xmlem::Document = xml_str.parse()?;
-                handle.write_all(doc.to_string_pretty().as_bytes())?;
+                handle.write_all(&encode_xml(&doc.to_string_pretty(), &cli.output_encoding))?;
             }
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the command line, returning what it wrote along with its result
+    fn run_args(args: &[&str]) -> (String, anyhow::Result<()>) {
+        let cli = Cli::try_parse_from(std::iter::once("omecat").chain(args.iter().copied()))
+            .unwrap_or_else(|e| panic!("{}", e));
+        let mut output = Vec::new();
+        let result = run(&cli, &mut output);
+        (String::from_utf8(output).unwrap(), result)
+    }
+
+    /// Runs the command line, failing the test if it fails
+    fn output(args: &[&str]) -> String {
+        let (output, result) = run_args(args);
+        result.unwrap();
+        output
+    }
+
+    #[test]
+    fn new_emits_a_structurally_valid_ome() {
+        let xml = output(&[
+            "new", "--size-x", "8", "--size-y", "6", "--size-z", "3", "--size-c", "2",
+        ]);
+        let ome: OME = from_str(&xml).unwrap();
+        assert_eq!(ome.xmlns.as_deref(), Some(OME_XMLNS));
+        let pixels = &ome.images[0].pixels;
+        assert_eq!((pixels.size_x, pixels.size_y), (8, 6));
+        assert_eq!(pixels.channels.len(), 2);
+        assert!(check_tiff_data_coverage("a.ome.tif", &ome, &[]).is_empty());
+        assert!(check_canonical_ids(&ome).is_empty());
+        assert!(check_duplicate_ids(&ome).is_empty());
+    }
+
+    /// A 4x3 uint16 image of SizeZ × SizeC × SizeT planes in consecutive IFDs
+    fn minimal_ome(size_z: usize, size_c: usize, size_t: usize, order: DimensionOrder) -> OME {
+        to_minimal_ome(&ImageConfig {
+            size_x: 4,
+            size_y: 3,
+            size_z,
+            size_c,
+            size_t,
+            pixel_type: "uint16".to_string(),
+            dimension_order: order,
+        })
+    }
+
+    /// The (z, c, t) of every plane with its IFD, sorted
+    fn plane_ifds(pixels: &Pixels) -> Vec<((usize, usize, usize), usize)> {
+        let mut planes: Vec<_> = tiff_data_planes(pixels, None)
+            .into_iter()
+            .map(|p| ((p.selection.z, p.selection.c, p.selection.t), p.ifd))
+            .collect();
+        planes.sort();
+        planes
+    }
+
+    #[test]
+    fn normalize_dimension_order_keeps_the_ifd_mapping() {
+        let mut pixels = minimal_ome(3, 2, 2, DimensionOrder::XYCZT).images[0]
+            .pixels
+            .clone();
+        let before = plane_ifds(&pixels);
+        normalize_dimension_order(&mut pixels, None);
+        assert_eq!(pixels.dimension_order, DimensionOrder::XYZCT);
+        assert_eq!(plane_ifds(&pixels), before);
+    }
+
+    /// A fresh directory under the system temp dir, removed with its files when dropped
+    struct TestDir(tempfile::TempDir);
+
+    impl std::ops::Deref for TestDir {
+        type Target = std::path::Path;
+
+        fn deref(&self) -> &std::path::Path {
+            self.0.path()
+        }
+    }
+
+    impl AsRef<std::path::Path> for TestDir {
+        fn as_ref(&self) -> &std::path::Path {
+            self.0.path()
+        }
+    }
+
+    /// A fresh directory for the test `name`
+    fn temp_dir(name: &str) -> TestDir {
+        let prefix = format!("omecat-{}-", name);
+        TestDir(tempfile::Builder::new().prefix(&prefix).tempdir().unwrap())
+    }
+
+    /// Points plane z of the single-channel image at IFD 0 of `file_name(z)`
+    fn one_file_per_z(ome: &mut OME, file_name: impl Fn(usize) -> String) {
+        let pixels = &mut ome.images[0].pixels;
+        pixels.tiff_data = (0..pixels.size_z)
+            .map(|z| TiffData {
+                ifd: Some(0),
+                plane_count: Some(1),
+                first_c: Some(0),
+                first_z: Some(z),
+                first_t: Some(0),
+                uuid: Some(Uuid {
+                    file_name: file_name(z),
+                    value: None,
+                }),
+            })
+            .collect();
+    }
+
+    #[test]
+    fn check_files_looks_inside_the_archive() {
+        let dir = temp_dir("check-files-archive");
+        let mut ome = minimal_ome(3, 1, 1, DimensionOrder::XYZCT);
+        one_file_per_z(&mut ome, |z| format!("s_z{}.tif", z));
+        let companion = dir.join("s.companion.ome");
+        std::fs::write(&companion, to_string(&ome).unwrap()).unwrap();
+        std::fs::write(dir.join("s_z0.tif"), b"").unwrap();
+        let archive = dir.join("s.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive).unwrap());
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("stack/s_z1.tif", options).unwrap();
+        zip.finish().unwrap();
+        let located = check_files(
+            companion.to_str().unwrap(),
+            &ome,
+            Some(archive.to_str().unwrap()),
+        )
+        .unwrap();
+        let names: Vec<&str> = located.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["s_z0.tif", "s_z1.tif", "s_z2.tif"]);
+        assert!(matches!(located[0].1, FileLocation::Disk));
+        assert!(matches!(located[1].1, FileLocation::Archive));
+        assert!(matches!(located[2].1, FileLocation::Missing));
+    }
+
+    #[test]
+    fn validate_reads_files_from_the_archive() {
+        let dir = temp_dir("validate-archive");
+        let mut ome = minimal_ome(2, 1, 1, DimensionOrder::XYZCT);
+        one_file_per_z(&mut ome, |z| format!("s_z{}.tif", z));
+        let companion = dir.join("s.companion.ome");
+        std::fs::write(&companion, to_string(&ome).unwrap()).unwrap();
+        write_tiff(&dir.join("s_z0.tif"), 1, "");
+        write_tiff(&dir.join("s_z1.tif"), 1, "");
+        let archive = dir.join("s.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive).unwrap());
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file("stack/s_z1.tif", options).unwrap();
+        let bytes = std::fs::read(dir.join("s_z1.tif")).unwrap();
+        std::io::Write::write_all(&mut zip, &bytes).unwrap();
+        zip.finish().unwrap();
+        std::fs::remove_file(dir.join("s_z1.tif")).unwrap();
+        let companion = companion.to_str().unwrap();
+        let args = [
+            "validate",
+            companion,
+            "--verify-plane-count-per-file",
+            "--check-page-numbers",
+        ];
+        let (report, result) = run_args(&args);
+        assert!(result.is_err());
+        assert!(report.contains("s_z1.tif can't be read"));
+        let archive = archive.to_str().unwrap();
+        let (report, result) = run_args(&[&args[..], &["--archive", archive]].concat());
+        assert_eq!(report, "");
+        result.unwrap();
+    }
+
+    /// Writes a TIFF of `planes` 4x3 uint16 pages, plane i holding the values i, i+1, ...,
+    /// letting `tags` add tags to each page
+    fn write_tiff_with(
+        path: &std::path::Path,
+        planes: usize,
+        mut tags: impl FnMut(
+            usize,
+            &mut tiff::encoder::DirectoryEncoder<
+                std::io::BufWriter<std::fs::File>,
+                tiff::encoder::TiffKindStandard,
+            >,
+        ),
+    ) {
+        let writer = std::fs::File::create(path)
+            .map(std::io::BufWriter::new)
+            .unwrap();
+        let mut encoder = tiff::encoder::TiffEncoder::new(writer).unwrap();
+        for i in 0..planes {
+            let mut image = encoder
+                .new_image::<tiff::encoder::colortype::Gray16>(4, 3)
+                .unwrap();
+            tags(i, image.encoder());
+            let data: Vec<u16> = (0..12).map(|p| p + i as u16).collect();
+            image.write_data(&data).unwrap();
+        }
+    }
+
+    /// Writes a TIFF of `planes` 4x3 uint16 pages with `description` on the first
+    fn write_tiff(path: &std::path::Path, planes: usize, description: &str) {
+        write_tiff_with(path, planes, |i, encoder| {
+            if i == 0 {
+                encoder
+                    .write_tag(tiff::tags::Tag::ImageDescription, Utf8Ascii(description))
+                    .unwrap();
+            }
+        });
+    }
+
+    /// Writes the stack `s_z1.ome.tif` to `s_z{size_z}.ome.tif` into `dir`, each file of
+    /// `planes` planes described by `ome`
+    fn write_stack(dir: &std::path::Path, size_z: usize, planes: usize, ome: &OME) {
+        let description = to_string(ome).unwrap();
+        for z in 1..=size_z {
+            write_tiff(&dir.join(format!("s_z{}.ome.tif", z)), planes, &description);
+        }
+    }
+
+    /// Runs `concat` on the stack of `size_z` files in `dir` followed by `args`
+    fn run_concat(
+        dir: &std::path::Path,
+        size_z: usize,
+        args: &[&str],
+    ) -> (String, anyhow::Result<()>) {
+        let first = dir.join("s_z1.ome.tif");
+        let size_z = size_z.to_string();
+        let stack = [
+            "concat",
+            first.to_str().unwrap(),
+            "--size-z",
+            &size_z,
+            "--filename-template",
+            "s_z{z}.ome.tif",
+        ];
+        run_args(&[&stack[..], args].concat())
+    }
+
+    /// Runs `concat` on the stack of `size_z` files in `dir`, failing the test if it fails
+    fn concat_stack(dir: &std::path::Path, size_z: usize, args: &[&str]) -> String {
+        let (output, result) = run_concat(dir, size_z, args);
+        result.unwrap();
+        output
+    }
+
+    #[test]
+    fn planecount_total_emits_one_tiff_data_per_file() {
+        let dir = temp_dir("planecount-total");
+        write_stack(&dir, 1, 100, &minimal_ome(1, 100, 1, DimensionOrder::XYZCT));
+        let xml = concat_stack(&dir, 1, &["--emit-tiff-data-planecount-total"]);
+        let ome: OME = from_str(&xml).unwrap();
+        let tiff_data = &ome.images[0].pixels.tiff_data;
+        assert_eq!(tiff_data.len(), 1);
+        assert_eq!(tiff_data[0].ifd, Some(0));
+        assert_eq!(tiff_data[0].plane_count, Some(100));
+        assert_eq!(
+            tiff_data[0].uuid.as_ref().unwrap().file_name,
+            "s_z1.ome.tif"
+        );
+    }
+
+    #[test]
+    fn planecount_total_keeps_an_order_with_c_before_z() {
+        let dir = temp_dir("planecount-total-order");
+        for (order, expected) in [
+            (DimensionOrder::XYCTZ, DimensionOrder::XYCTZ),
+            (DimensionOrder::XYZCT, DimensionOrder::XYCZT),
+        ] {
+            write_stack(&dir, 2, 2, &minimal_ome(1, 2, 1, order));
+            let xml = concat_stack(&dir, 2, &["--emit-tiff-data-planecount-total"]);
+            let ome: OME = from_str(&xml).unwrap();
+            let pixels = &ome.images[0].pixels;
+            assert_eq!(pixels.dimension_order, expected);
+            assert_eq!(
+                plane_ifds(pixels),
+                [
+                    ((0, 0, 0), 0),
+                    ((0, 1, 0), 1),
+                    ((1, 0, 0), 0),
+                    ((1, 1, 0), 1)
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn dimension_order_must_start_with_xy() {
+        let e = "ZYXCT".parse::<DimensionOrder>().unwrap_err();
+        assert!(e.contains("X and Y must be the two fastest"), "{}", e);
+        let xml = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT))
+            .unwrap()
+            .replace("DimensionOrder=\"XYZCT\"", "DimensionOrder=\"ZYXCT\"");
+        assert!(from_str::<OME>(&xml).is_err());
+    }
+
+    #[test]
+    fn write_back_copy_tags_keeps_software() {
+        let dir = temp_dir("copy-tags");
+        let file = dir.join("in.ome.tif");
+        let xml = to_string(&minimal_ome(2, 1, 1, DimensionOrder::XYZCT)).unwrap();
+        write_tiff_with(&file, 2, |i, encoder| {
+            if i == 0 {
+                encoder
+                    .write_tag(tiff::tags::Tag::ImageDescription, Utf8Ascii(&xml))
+                    .unwrap();
+            }
+            encoder
+                .write_tag(tiff::tags::Tag::Software, "scope 2.1")
+                .unwrap();
+        });
+        let target = dir.join("out.ome.tif");
+        let (file, target) = (file.to_str().unwrap(), target.to_str().unwrap());
+        output(&["write-back", file, target, "--copy-tags"]);
+        let reader = std::fs::File::open(target).unwrap();
+        let mut decoder = tiff::decoder::Decoder::new(reader).unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                decoder
+                    .get_tag_ascii_string(tiff::tags::Tag::Software)
+                    .unwrap(),
+                "scope 2.1"
+            );
+            if decoder.more_images() {
+                decoder.next_image().unwrap();
+            }
+        }
+        assert!(get_image_description(target, 1).unwrap().contains("<OME"));
+    }
+
+    #[test]
+    fn from_imagej_reads_the_hyperstack_dimensions() {
+        let dir = temp_dir("from-imagej");
+        let file = dir.join("ij.tif");
+        let description = "ImageJ=1.53t\nimages=6\nchannels=2\nslices=3\nhyperstack=true\nunit=\\u00B5m\nspacing=0.5\n";
+        write_tiff(&file, 6, description);
+        let xml = output(&["--from-imagej", file.to_str().unwrap()]);
+        let ome: OME = from_str(&xml).unwrap();
+        let pixels = &ome.images[0].pixels;
+        assert_eq!((pixels.size_z, pixels.size_c, pixels.size_t), (3, 2, 1));
+        assert_eq!(pixels.dimension_order, DimensionOrder::XYCZT);
+        assert_eq!(pixels.r#type, "uint16");
+        assert_eq!(pixels.physical_size_z, Some(0.5));
+        assert_eq!(pixels.physical_size_z_unit, Some(LengthUnit("µm")));
+    }
+
+    #[test]
+    fn trim_channels_truncates_to_size_c() {
+        let mut pixels = minimal_ome(1, 4, 1, DimensionOrder::XYZCT).images[0]
+            .pixels
+            .clone();
+        pixels.size_c = 2;
+        trim_channels_to_size_c(0, &mut pixels, &Warnings { json: false });
+        let ids: Vec<&str> = pixels.channels.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, ["Channel:0:0", "Channel:0:1"]);
+    }
+
+    #[test]
+    fn fake_descriptor_encodes_non_default_dimensions() {
+        let mut pixels = to_minimal_ome(&ImageConfig {
+            size_x: 512,
+            size_y: 512,
+            size_z: 10,
+            size_c: 2,
+            size_t: 1,
+            pixel_type: "uint8".to_string(),
+            dimension_order: DimensionOrder::XYZCT,
+        })
+        .images[0]
+            .pixels
+            .clone();
+        assert_eq!(
+            to_fake_descriptor("test", &pixels),
+            "test&sizeZ=10&sizeC=2.fake"
+        );
+        pixels.r#type = "uint16".to_string();
+        pixels.dimension_order = DimensionOrder::XYCZT;
+        assert_eq!(
+            to_fake_descriptor("test", &pixels),
+            "test&sizeZ=10&sizeC=2&pixelType=uint16&dimOrder=XYCZT.fake"
+        );
+    }
+
+    #[test]
+    fn physical_sizes_parse_in_any_attribute_order() {
+        let xml = r#"<OME xmlns="http://www.openmicroscopy.org/Schemas/OME/2016-06">
+  <Image ID="Image:0">
+    <Pixels PhysicalSizeZUnit="nm" SizeY="3" PhysicalSizeX="0.5" DimensionOrder="XYZCT"
+        PhysicalSizeYUnit="mm" SizeT="1" PhysicalSizeZ="200" Type="uint16" SizeC="1"
+        PhysicalSizeY="0.25" ID="Pixels:0" SizeZ="1" PhysicalSizeXUnit="µm" SizeX="4">
+      <Channel ID="Channel:0:0" SamplesPerPixel="1"><LightPath/></Channel>
+      <TiffData/>
+    </Pixels>
+  </Image>
+</OME>"#;
+        let ome: OME = from_str(xml).unwrap();
+        let pixels = &ome.images[0].pixels;
+        assert_eq!(pixels.physical_size_x, Some(0.5));
+        assert_eq!(pixels.physical_size_x_unit, Some(LengthUnit("µm")));
+        assert_eq!(pixels.physical_size_y, Some(0.25));
+        assert_eq!(pixels.physical_size_y_unit, Some(LengthUnit("mm")));
+        assert_eq!(pixels.physical_size_z, Some(200.0));
+        assert_eq!(pixels.physical_size_z_unit, Some(LengthUnit("nm")));
+        assert_eq!((pixels.size_x, pixels.size_y), (4, 3));
+    }
+
+    #[test]
+    fn missing_physical_size_z_is_reported() {
+        let dir = temp_dir("missing-physical-sizes");
+        let mut ome = minimal_ome(2, 1, 1, DimensionOrder::XYZCT);
+        let pixels = &mut ome.images[0].pixels;
+        pixels.physical_size_x = Some(0.5);
+        pixels.physical_size_x_unit = Some(LengthUnit("µm"));
+        pixels.physical_size_y = Some(0.5);
+        pixels.physical_size_y_unit = Some(LengthUnit("µm"));
+        let issues = check_physical_sizes(&ome, Severity::Warning);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(
+            issues[0].message,
+            "Image:0 is uncalibrated along Z (no PhysicalSize)"
+        );
+        let file = dir.join("a.companion.ome");
+        std::fs::write(&file, to_string(&ome).unwrap()).unwrap();
+        let file = file.to_str().unwrap();
+        let (report, result) = run_args(&["validate", file, "--report-missing-physical-sizes"]);
+        assert!(result.is_ok());
+        assert!(report.starts_with("warning: Image:0 is uncalibrated along Z"));
+        let (report, result) = run_args(&["validate", file, "--require-physical-sizes"]);
+        assert!(result.is_err());
+        assert!(report.starts_with("error: Image:0 is uncalibrated along Z"));
+    }
+
+    #[test]
+    fn master_embeds_the_companion_with_the_multifile_uuids() {
+        let dir = temp_dir("master");
+        let uuids = [
+            Some("urn:uuid:11111111-1111-1111-1111-111111111111"),
+            Some("urn:uuid:22222222-2222-2222-2222-222222222222"),
+            None,
+        ];
+        for (z, uuid) in uuids.iter().enumerate() {
+            let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+            ome.uuid = uuid.map(String::from);
+            let file = dir.join(format!("s_z{}.ome.tif", z + 1));
+            write_tiff(&file, 1, &to_string(&ome).unwrap());
+        }
+        let master = dir.join("master.ome.tif");
+        let master = master.to_str().unwrap();
+        concat_stack(&dir, 3, &["--master", master]);
+        let ome: OME = from_str(&get_image_description(master, 1).unwrap()).unwrap();
+        let root = ome.uuid.clone().unwrap();
+        assert!(root.starts_with("urn:uuid:"));
+        assert_ne!(Some(root.as_str()), uuids[0]);
+        let references: Vec<(&str, Option<&str>)> = ome.images[0]
+            .pixels
+            .tiff_data
+            .iter()
+            .map(|tiff_data| {
+                let uuid = tiff_data.uuid.as_ref().unwrap();
+                (uuid.file_name.as_str(), uuid.value.as_deref())
+            })
+            .collect();
+        assert_eq!(references[0], ("master.ome.tif", Some(root.as_str())));
+        assert_eq!(references[1], ("s_z2.ome.tif", uuids[1]));
+        assert_eq!(references[2], ("s_z3.ome.tif", None));
+        assert_eq!(full_resolution_ifds(master).unwrap(), [0]);
+    }
+
+    #[test]
+    fn pixel_type_is_inferred_from_a_16_bit_tiff() {
+        let dir = temp_dir("pixel-type-from-tiff");
+        let file = dir.join("a.ome.tif");
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        ome.images[0].pixels.r#type = "float".to_string();
+        write_tiff(&file, 1, &to_string(&ome).unwrap());
+        let xml = output(&["--pixel-type-from-tiff", file.to_str().unwrap()]);
+        let ome: OME = from_str(&xml).unwrap();
+        assert_eq!(ome.images[0].pixels.r#type, "uint16");
+    }
+
+    /// A single-plane image whose first channel references `Annotation:0` and whose
+    /// Image references `Annotation:1`, with `annotations` as StructuredAnnotations
+    fn annotated_ome_xml(annotations: &str) -> String {
+        format!(
+            r#"<OME xmlns="http://www.openmicroscopy.org/Schemas/OME/2016-06">
+  <Image ID="Image:0">
+    <Pixels ID="Pixels:0" Type="uint16" SizeX="4" SizeY="3" SizeZ="1" SizeC="1" SizeT="1" DimensionOrder="XYZCT">
+      <Channel ID="Channel:0:0" SamplesPerPixel="1">
+        <AnnotationRef ID="Annotation:0"/>
+        <LightPath/>
+      </Channel>
+      <TiffData/>
+    </Pixels>
+    <AnnotationRef ID="Annotation:1"/>
+  </Image>
+  <StructuredAnnotations>{}</StructuredAnnotations>
+</OME>"#,
+            annotations
+        )
+    }
+
+    #[test]
+    fn channel_annotation_ref_roundtrips_before_light_path() {
+        let xml = annotated_ome_xml(
+            r#"<CommentAnnotation ID="Annotation:0"><Value>bleached</Value></CommentAnnotation>
+  <TagAnnotation ID="Annotation:1"><Value>qc</Value></TagAnnotation>"#,
+        );
+        let mut ome: OME = from_str(&xml).unwrap();
+        drop_dangling_annotation_refs(&mut ome, &Warnings { json: false });
+        let out = to_string(&ome).unwrap();
+        let channel = &out[out.find("<Channel ").unwrap()..out.find("</Channel>").unwrap()];
+        assert!(
+            channel
+                .find("<AnnotationRef ID=\"Annotation:0\"/>")
+                .unwrap()
+                < channel.find("<LightPath/>").unwrap()
+        );
+        let ome: OME = from_str(&out).unwrap();
+        assert_eq!(
+            ome.images[0].pixels.channels[0].annotation_refs[0].id,
+            "Annotation:0"
+        );
+        assert_eq!(ome.images[0].annotation_refs[0].id, "Annotation:1");
+    }
+
+    #[test]
+    fn unmodeled_annotations_are_kept_with_their_refs() {
+        let annotations = r#"<XMLAnnotation ID="Annotation:0" Namespace="openmicroscopy.org/OriginalMetadata"><Value><OriginalMetadata xmlns="openmicroscopy.org/OriginalMetadata"><Key>Lens</Key><Value>60x &amp; oil</Value></OriginalMetadata></Value></XMLAnnotation><FileAnnotation ID="Annotation:1"><BinaryFile FileName="notes.txt" Size="0"><External href="notes.txt" SHA1="00"/></BinaryFile></FileAnnotation>"#;
+        let xml = annotated_ome_xml(annotations).replace(
+            r#"    <AnnotationRef ID="Annotation:1"/>"#,
+            r#"    <AnnotationRef ID="Annotation:1"/>
+    <AnnotationRef ID="Annotation:2"/>"#,
+        );
+        let mut ome: OME = from_str(&xml).unwrap();
+        drop_dangling_annotation_refs(&mut ome, &Warnings { json: false });
+        let out = to_string(&ome).unwrap();
+        assert!(out.contains(&format!(
+            "<StructuredAnnotations>{}</StructuredAnnotations>",
+            annotations
+        )));
+        assert!(out.contains("<AnnotationRef ID=\"Annotation:0\"/>"));
+        assert!(out.contains("<AnnotationRef ID=\"Annotation:1\"/>"));
+        assert!(!out.contains("Annotation:2"));
+    }
+
+    #[test]
+    fn dimension_sizes_come_from_the_files() {
+        let dir = temp_dir("dimension-sizes-from-files");
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        ome.images[0].pixels.size_x = 100;
+        ome.images[0].pixels.size_y = 200;
+        ome.images[0].pixels.r#type = "uint8".to_string();
+        write_stack(&dir, 3, 1, &ome);
+        // No --size-z, which is discovered from the files
+        let xml = output(&[
+            "concat",
+            dir.join("s_z1.ome.tif").to_str().unwrap(),
+            "--filename-template",
+            "s_z{z}.ome.tif",
+            "--emit-dimension-sizes-from-files",
+        ]);
+        let ome: OME = from_str(&xml).unwrap();
+        let pixels = &ome.images[0].pixels;
+        assert_eq!((pixels.size_x, pixels.size_y, pixels.size_z), (4, 3, 3));
+        assert_eq!(pixels.r#type, "uint16");
+    }
+
+    #[test]
+    fn gzip_output_decompresses_to_the_xml() {
+        let dir = temp_dir("gzip");
+        let args = ["new", "--size-x", "8", "--size-y", "6"];
+        let expected = output(&args);
+        let path = dir.join("new.ome");
+        let cli = Cli::try_parse_from(["omecat"].iter().chain(&args)).unwrap();
+        let mut handle = Output::open(path.to_str(), true).unwrap();
+        run(&cli, &mut handle).unwrap();
+        handle.finish().unwrap();
+        assert!(!path.exists());
+        let reader = std::fs::File::open(dir.join("new.ome.gz")).unwrap();
+        let mut xml = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(reader), &mut xml).unwrap();
+        assert_eq!(xml, expected);
+    }
+
+    #[test]
+    fn failing_command_leaves_the_previous_output_intact() {
+        let dir = temp_dir("output-intact");
+        let path = dir.join("out.ome");
+        std::fs::write(&path, "previous").unwrap();
+        let write = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["omecat"].iter().chain(args)).unwrap();
+            let mut handle = Output::open(path.to_str(), false).unwrap();
+            run(&cli, &mut handle).and_then(|_| handle.finish())
+        };
+        let missing = dir.join("missing.ome.tif");
+        assert!(write(&[missing.to_str().unwrap()]).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        write(&["new", "--size-x", "8", "--size-y", "6"]).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().starts_with("<OME"));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    /// Writes a `z`-plane stack as one single-plane file per z, each carrying `page`
+    /// as its PageNumber, and returns the path of the concatenated companion
+    fn paged_stack(dir: &std::path::Path, size_z: usize, page: impl Fn(usize) -> u16) -> String {
+        let description = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT)).unwrap();
+        for z in 0..size_z {
+            let file = dir.join(format!("s_z{}.ome.tif", z + 1));
+            write_tiff_with(&file, 1, |_, encoder| {
+                encoder
+                    .write_tag(
+                        tiff::tags::Tag::ImageDescription,
+                        Utf8Ascii(description.as_str()),
+                    )
+                    .unwrap();
+                encoder
+                    .write_tag(tiff::tags::Tag::Unknown(297), &[page(z), 1][..])
+                    .unwrap();
+            });
+        }
+        let xml = concat_stack(dir, size_z, &[]);
+        let companion = dir.join("s.companion.ome");
+        std::fs::write(&companion, xml).unwrap();
+        companion.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn page_numbers_count_from_zero_in_each_file() {
+        let dir = temp_dir("page-numbers");
+        let companion = paged_stack(&dir, 3, |_| 0);
+        let (_, result) = run_args(&["validate", &companion, "--check-page-numbers"]);
+        result.unwrap();
+    }
+
+    #[test]
+    fn conflicting_page_number_is_reported() {
+        let dir = temp_dir("page-numbers-conflict");
+        let companion = paged_stack(&dir, 3, |z| if z == 1 { 1 } else { 0 });
+        let (report, result) = run_args(&["validate", &companion, "--check-page-numbers"]);
+        assert!(result.is_err());
+        assert!(report.contains("s_z2.ome.tif IFD 0 has PageNumber 1 but holds its plane 0"));
+        assert_eq!(report.lines().count(), 1);
+    }
+
+    #[test]
+    fn missing_file_is_reported_by_the_page_number_check() {
+        let dir = temp_dir("page-numbers-missing");
+        let companion = paged_stack(&dir, 3, |_| 0);
+        std::fs::remove_file(dir.join("s_z3.ome.tif")).unwrap();
+        let (report, result) = run_args(&["validate", &companion, "--check-page-numbers"]);
+        assert!(result.is_err());
+        assert!(report.contains("s_z3.ome.tif can't be read for PageNumber"));
+    }
+
+    #[test]
+    fn info_cuts_the_channel_listing_at_max_list() {
+        let dir = temp_dir("max-list");
+        let file = dir.join("a.ome.xml");
+        let xml = to_string(&minimal_ome(1, 50, 1, DimensionOrder::XYZCT)).unwrap();
+        std::fs::write(&file, xml).unwrap();
+        let info = output(&["info", file.to_str().unwrap(), "--max-list", "5"]);
+        let listed: Vec<&str> = info
+            .lines()
+            .skip_while(|line| !line.starts_with("  Channels:"))
+            .skip(1)
+            .take_while(|line| line.starts_with("    "))
+            .collect();
+        assert_eq!(listed.len(), 6);
+        assert!(listed[4].starts_with("    4: "));
+        assert_eq!(listed[5], "    … and 45 more");
+    }
+
+    #[test]
+    fn ifd_to_selection_inverts_the_dimension_order() {
+        use DimensionOrder::*;
+        for order in [XYZCT, XYZTC, XYCTZ, XYCZT, XYTCZ, XYTZC] {
+            let pixels = minimal_ome(2, 3, 4, order).images.remove(0).pixels;
+            let mut ifds = Vec::new();
+            for z in 0..2 {
+                for c in 0..3 {
+                    for t in 0..4 {
+                        let selection = Selection { t, z, c };
+                        let ifd = get_relative_ifd_index(selection, &pixels);
+                        assert_eq!(ifd_to_selection(ifd, &pixels).unwrap(), selection);
+                        ifds.push(ifd);
+                    }
+                }
+            }
+            ifds.sort();
+            assert_eq!(ifds, (0..24).collect::<Vec<_>>());
+            assert!(ifd_to_selection(24, &pixels).is_err());
+        }
+    }
+
+    #[test]
+    fn image_and_channel_names_are_optional() {
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        let xml = to_string(&ome).unwrap().replace(" Name=\"Image:0\"", "");
+        let xml = xml.replace(" Name=\"Channel:0:0\"", "");
+        assert!(!xml.contains("Name="));
+        let parsed: OME = from_str(&xml).unwrap();
+        assert_eq!(parsed.images[0].name, None);
+        assert_eq!(parsed.images[0].pixels.channels[0].name, None);
+        assert_eq!(to_string(&parsed).unwrap(), xml);
+        ome.images[0].name = None;
+        ome.images[0].pixels.channels[0].name = None;
+        assert_eq!(to_string(&ome).unwrap(), xml);
+    }
+
+    #[test]
+    fn plane_position_z_includes_the_stage_offset() {
+        let dir = temp_dir("stage-position");
+        write_stack(&dir, 3, 1, &minimal_ome(1, 1, 1, DimensionOrder::XYZCT));
+        let xml = concat_stack(
+            &dir,
+            3,
+            &[
+                "--physical-size-z",
+                "0.5",
+                "--emit-plane-position-from-stage",
+                "--stage-position-z",
+                "10",
+            ],
+        );
+        let ome: OME = from_str(&xml).unwrap();
+        let positions: Vec<(usize, Option<f64>)> = ome.images[0]
+            .pixels
+            .planes
+            .iter()
+            .map(|plane| (plane.the_z, plane.position_z))
+            .collect();
+        assert_eq!(
+            positions,
+            [(0, Some(10.0)), (1, Some(10.5)), (2, Some(11.0))]
+        );
+        let unit = ome.images[0].pixels.planes[0].position_z_unit;
+        assert_eq!(unit, Some(LengthUnit("µm")));
+    }
+
+    #[test]
+    fn length_units_tolerate_whitespace_and_spelled_out_prefixes() {
+        assert_eq!(" µm ".parse::<LengthUnit>().unwrap(), LengthUnit("µm"));
+        assert_eq!("micro m".parse::<LengthUnit>().unwrap(), LengthUnit("µm"));
+        assert_eq!(
+            "nano  meter".parse::<LengthUnit>().unwrap(),
+            LengthUnit("nm")
+        );
+        let error = "furlong".parse::<LengthUnit>().unwrap_err();
+        assert_eq!(error, "Unknown length unit \"furlong\"");
+    }
+
+    #[test]
+    fn dump_raw_description_prints_the_stored_bytes() {
+        let dir = temp_dir("dump-raw-description");
+        let file = dir.join("a.tif");
+        let description = "<OME>5 µm</OME>";
+        write_tiff_with(&file, 2, |i, encoder| {
+            if i == 1 {
+                encoder
+                    .write_tag(tiff::tags::Tag::ImageDescription, Utf8Ascii(description))
+                    .unwrap();
+            }
+        });
+        let file = file.to_str().unwrap();
+        let raw = output(&["dump-raw-description", file, "--ifd", "1"]);
+        assert_eq!(raw.as_bytes(), format!("{}\0", description).as_bytes());
+        let hex = output(&["dump-raw-description", file, "--ifd", "1", "--hex"]);
+        assert_eq!(
+            hex,
+            "00000000  3c 4f 4d 45 3e 35 20 c2 b5 6d 3c 2f 4f 4d 45 3e  <OME>5 ..m</OME>\n\
+             00000010  00                                               .\n"
+        );
+        let (_, result) = run_args(&["dump-raw-description", file]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn base_resolution_ifds_skip_the_reduced_resolutions() {
+        let dir = temp_dir("base-resolution");
+        let description = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT)).unwrap();
+        for z in 1..=2 {
+            // A reduced resolution stored ahead of the full-resolution plane
+            write_tiff_with(&dir.join(format!("s_z{}.ome.tif", z)), 2, |i, encoder| {
+                if i == 0 {
+                    encoder
+                        .write_tag(
+                            tiff::tags::Tag::ImageDescription,
+                            Utf8Ascii(description.as_str()),
+                        )
+                        .unwrap();
+                    encoder
+                        .write_tag(tiff::tags::Tag::NewSubfileType, 1u32)
+                        .unwrap();
+                }
+            });
+        }
+        let first = dir.join("s_z1.ome.tif");
+        assert_eq!(full_resolution_ifds(first.to_str().unwrap()).unwrap(), [1]);
+        let xml = concat_stack(&dir, 2, &["--base-resolution-ifds"]);
+        let ome: OME = from_str(&xml).unwrap();
+        let ifds: Vec<Option<usize>> = ome.images[0]
+            .pixels
+            .tiff_data
+            .iter()
+            .map(|tiff_data| tiff_data.ifd)
+            .collect();
+        assert_eq!(ifds, [Some(1), Some(1)]);
+    }
+
+    #[test]
+    fn only_image_selects_by_id() {
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        let mut second = ome.images[0].clone();
+        second.id = "Image:1".to_string();
+        ome.images.push(second);
+        let select = |id: &str| {
+            ImageSelector {
+                image_index: 0,
+                only_image: Some(id.to_string()),
+            }
+            .resolve(&ome)
+        };
+        assert_eq!(select("Image:1").unwrap(), 1);
+        assert_eq!(select("Image:0").unwrap(), 0);
+        assert_eq!(
+            select("Image:2").unwrap_err().to_string(),
+            "No Image with ID Image:2 (available: Image:0, Image:1)"
+        );
+    }
+
+    #[test]
+    fn emission_wavelength_and_its_unit_roundtrip() {
+        let dir = temp_dir("wavelengths");
+        let file = dir.join("a.ome.tif");
+        let xml = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT)).unwrap();
+        let xml = xml.replace(
+            " SamplesPerPixel=\"1\"",
+            " SamplesPerPixel=\"1\" EmissionWavelength=\"0.52\" EmissionWavelengthUnit=\"µm\"",
+        );
+        write_tiff(&file, 1, &xml);
+        let file = file.to_str().unwrap();
+        let xml = output(&["--excitation-wavelength", "488", file]);
+        let ome: OME = from_str(&xml).unwrap();
+        let channel = &ome.images[0].pixels.channels[0];
+        assert_eq!(channel.emission_wavelength, Some(0.52));
+        assert_eq!(channel.emission_wavelength_unit, Some(LengthUnit("µm")));
+        assert_eq!(channel.excitation_wavelength, Some(488.0));
+        assert_eq!(channel.excitation_wavelength_unit, Some(LengthUnit("nm")));
+    }
+
+    #[test]
+    fn batch_reports_a_broken_input_and_processes_the_rest() {
+        let dir = temp_dir("batch");
+        let description = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT)).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::create_dir(dir.join(name)).unwrap();
+            let file = dir.join(name).join("s_z1.ome.tif");
+            if name == "b" {
+                std::fs::write(&file, b"not a tiff").unwrap();
+            } else {
+                write_tiff(&file, 1, &description);
+            }
+        }
+        let pattern = dir.join("*").join("s_z1.ome.tif");
+        let (report, result) = run_args(&[
+            "batch",
+            pattern.to_str().unwrap(),
+            "--size-z",
+            "1",
+            "--filename-template",
+            "s_z{z}.ome.tif",
+        ]);
+        assert_eq!(result.unwrap_err().to_string(), "1 of 3 file(s) failed");
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("ok: "));
+        assert!(lines[1].starts_with("failed: ") && lines[1].contains("b/s_z1.ome.tif"));
+        assert!(lines[2].starts_with("ok: "));
+        for name in ["a", "c"] {
+            let companion = std::fs::read_to_string(dir.join(name).join("s_z1.companion.ome"));
+            let _: OME = from_str(&companion.unwrap()).unwrap();
+        }
+        assert!(!dir.join("b").join("s_z1.companion.ome").exists());
+    }
+
+    #[test]
+    fn batch_counts_an_input_with_several_timepoints_as_failed() {
+        let dir = temp_dir("batch-size-t");
+        for (name, size_t) in [("a", 1), ("b", 2), ("c", 1)] {
+            std::fs::create_dir(dir.join(name)).unwrap();
+            let description = to_string(&minimal_ome(1, 1, size_t, DimensionOrder::XYZCT));
+            let file = dir.join(name).join("s_z1.ome.tif");
+            write_tiff(&file, size_t, &description.unwrap());
+        }
+        let pattern = dir.join("*").join("s_z1.ome.tif");
+        let (report, result) = run_args(&[
+            "batch",
+            pattern.to_str().unwrap(),
+            "--size-z",
+            "1",
+            "--filename-template",
+            "s_z{z}.ome.tif",
+        ]);
+        assert_eq!(result.unwrap_err().to_string(), "1 of 3 file(s) failed");
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[0].starts_with("ok: "));
+        assert!(
+            lines[1].starts_with("failed: ")
+                && lines[1].ends_with("SizeT 2, a stack file per z holds a single timepoint")
+        );
+        assert!(lines[2].starts_with("ok: "));
+        let (_, result) = run_args(&[
+            "concat",
+            dir.join("a").join("s_z1.ome.tif").to_str().unwrap(),
+            "--size-z",
+            "1000",
+            "--filename-template",
+            "s_z{z}.ome.tif",
+        ]);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Invalid size_z 1000, expected 1 to 999"
+        );
+    }
+
+    #[test]
+    fn annotation_refs_keep_their_order_and_targets() {
+        let xml = annotated_ome_xml(
+            r#"<CommentAnnotation ID="Annotation:0"><Value>first</Value></CommentAnnotation>
+  <MapAnnotation ID="Annotation:1"><Value><M K="a">1</M></Value></MapAnnotation>
+  <TagAnnotation ID="Annotation:2"><Value>qc</Value></TagAnnotation>"#,
+        )
+        .replace(
+            r#"    <AnnotationRef ID="Annotation:1"/>"#,
+            r#"    <AnnotationRef ID="Annotation:2"/>
+    <AnnotationRef ID="Annotation:0"/>
+    <AnnotationRef ID="Annotation:1"/>"#,
+        );
+        let mut ome: OME = from_str(&xml).unwrap();
+        drop_dangling_annotation_refs(&mut ome, &Warnings { json: false });
+        let out = to_string(&ome).unwrap();
+        let ids: Vec<&str> = out
+            .split("ID=\"Annotation:")
+            .skip(1)
+            .map(|rest| &rest[..1])
+            .collect();
+        // The channel's reference, the image's three, then the annotations themselves
+        assert_eq!(ids, ["0", "2", "0", "1", "0", "1", "2"]);
+        assert_eq!(to_string(&from_str::<OME>(&out).unwrap()).unwrap(), out);
+    }
+
+    #[test]
+    fn bin_data_roundtrips_in_the_requested_byte_order() {
+        let dir = temp_dir("bin-data");
+        let file = dir.join("a.ome.tif");
+        let description = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT)).unwrap();
+        write_tiff(&file, 1, &description);
+        let file = file.to_str().unwrap();
+        for (endianness, big_endian) in [("big", true), ("little", false)] {
+            let xml = output(&[
+                "--embed-bin-data",
+                "--emit-tiff-endianness",
+                endianness,
+                file,
+            ]);
+            let ome: OME = from_str(&xml).unwrap();
+            let pixels = &ome.images[0].pixels;
+            assert!(pixels.tiff_data.is_empty());
+            assert_eq!(pixels.bin_data.len(), 1);
+            assert_eq!(pixels.bin_data[0].big_endian, big_endian);
+            let expected: Vec<u8> = (0..12u16)
+                .flat_map(|v| {
+                    if big_endian {
+                        v.to_be_bytes()
+                    } else {
+                        v.to_le_bytes()
+                    }
+                })
+                .collect();
+            assert_eq!(pixels.bin_data[0].decode().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn description_is_read_from_compressed_files() {
+        let dir = temp_dir("compressed");
+        let description = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT)).unwrap();
+        let lzw = dir.join("lzw.ome.tif");
+        let writer = std::fs::File::create(&lzw)
+            .map(std::io::BufWriter::new)
+            .unwrap();
+        let mut encoder = tiff::encoder::TiffEncoder::new(writer).unwrap();
+        let mut image = encoder
+            .new_image_with_compression::<tiff::encoder::colortype::Gray16, _>(
+                4,
+                3,
+                tiff::encoder::compression::Lzw,
+            )
+            .unwrap();
+        image
+            .encoder()
+            .write_tag(
+                tiff::tags::Tag::ImageDescription,
+                Utf8Ascii(description.as_str()),
+            )
+            .unwrap();
+        image.write_data(&[0u16; 12]).unwrap();
+        drop(encoder);
+        assert_eq!(
+            get_image_description(lzw.to_str().unwrap(), 1).unwrap(),
+            description
+        );
+        // A compression the tiff crate can't decode (JPEG 2000), patched into the
+        // Compression entry: tag 259, SHORT, count 1, value 1
+        let unsupported = dir.join("jp2k.ome.tif");
+        write_tiff(&unsupported, 1, &description);
+        let mut bytes = std::fs::read(&unsupported).unwrap();
+        let entry = [3, 1, 3, 0, 1, 0, 0, 0, 1, 0];
+        let at = bytes.windows(10).position(|w| w == entry).unwrap();
+        bytes[at + 8..at + 10].copy_from_slice(&34712u16.to_le_bytes());
+        std::fs::write(&unsupported, bytes).unwrap();
+        assert_eq!(
+            get_image_description(unsupported.to_str().unwrap(), 1).unwrap(),
+            description
+        );
+    }
+
+    #[test]
+    fn latin1_output_declares_and_uses_the_encoding() {
+        let dir = temp_dir("latin1");
+        let file = dir.join("a.ome.tif");
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        ome.images[0].name = Some("Zelle µ → 2".to_string());
+        write_tiff(&file, 1, &to_string(&ome).unwrap());
+        let cli = Cli::try_parse_from([
+            "omecat",
+            "--output-encoding",
+            "ISO-8859-1",
+            file.to_str().unwrap(),
+        ])
+        .unwrap();
+        let mut bytes = Vec::new();
+        run(&cli, &mut bytes).unwrap();
+        let declaration = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?>\n";
+        assert!(bytes.starts_with(declaration));
+        let name = "Name=\"Zelle \u{b5} &#8594; 2\"";
+        let latin1: Vec<u8> = name.chars().map(|c| c as u8).collect();
+        assert!(bytes.windows(latin1.len()).any(|w| w == latin1));
+        assert!(std::str::from_utf8(&bytes).is_err());
+    }
+
+    #[test]
+    fn fill_dimensions_reads_the_referenced_file() {
+        let dir = temp_dir("fill-dimensions");
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        ome.images[0].pixels.size_x = 0;
+        ome.images[0].pixels.size_y = 0;
+        one_file_per_z(&mut ome, |_| "plane.ome.tif".to_string());
+        let master = dir.join("master.ome.tif");
+        write_tiff(&master, 1, &to_string(&ome).unwrap());
+        write_tiff(&dir.join("plane.ome.tif"), 1, "");
+        let master = master.to_str().unwrap();
+        let xml = output(&["--fill-dimensions", master]);
+        let ome: OME = from_str(&xml).unwrap();
+        assert_eq!(
+            (ome.images[0].pixels.size_x, ome.images[0].pixels.size_y),
+            (4, 3)
+        );
+        std::fs::remove_file(dir.join("plane.ome.tif")).unwrap();
+        let (_, result) = run_args(&["--fill-dimensions", master]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn tiff_data_sorts_by_each_key() {
+        let tiff_data = |ifd: usize, z: usize, c: usize, file_name: Option<&str>| TiffData {
+            ifd: Some(ifd),
+            plane_count: Some(1),
+            first_c: Some(c),
+            first_z: Some(z),
+            first_t: Some(0),
+            uuid: file_name.map(|file_name| Uuid {
+                file_name: file_name.to_string(),
+                value: None,
+            }),
+        };
+        let mut pixels = minimal_ome(2, 2, 1, DimensionOrder::XYZCT)
+            .images
+            .remove(0)
+            .pixels;
+        pixels.tiff_data = vec![
+            tiff_data(3, 1, 1, Some("s_10.tif")),
+            tiff_data(0, 0, 1, Some("s_2.tif")),
+            tiff_data(2, 1, 0, None),
+            tiff_data(1, 0, 0, Some("s_1.tif")),
+        ];
+        let order = |pixels: &Pixels| -> Vec<usize> {
+            pixels.tiff_data.iter().map(|t| t.ifd.unwrap()).collect()
+        };
+        sort_tiff_data(&mut pixels, "ifd");
+        assert_eq!(order(&pixels), [0, 1, 2, 3]);
+        sort_tiff_data(&mut pixels, "zct");
+        assert_eq!(order(&pixels), [1, 0, 2, 3]);
+        sort_tiff_data(&mut pixels, "filename");
+        assert_eq!(order(&pixels), [2, 1, 0, 3]);
+    }
+
+    /// An OME of one plane held by `other.ome.tif`, referenced with a UUID value
+    fn uuid_ome_xml() -> String {
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        one_file_per_z(&mut ome, |_| "other.ome.tif".to_string());
+        to_string(&ome).unwrap().replace(
+            r#"<UUID FileName="other.ome.tif"/>"#,
+            r#"<UUID FileName="other.ome.tif">urn:uuid:11111111-1111-1111-1111-111111111111</UUID>"#,
+        )
+    }
+
+    #[test]
+    fn uuid_keeps_its_file_name_and_value() {
+        let xml = uuid_ome_xml();
+        let ome: OME = from_str(&xml).unwrap();
+        let uuid = ome.images[0].pixels.tiff_data[0].uuid.as_ref().unwrap();
+        assert_eq!(uuid.file_name, "other.ome.tif");
+        assert_eq!(
+            uuid.value.as_deref(),
+            Some("urn:uuid:11111111-1111-1111-1111-111111111111")
+        );
+        assert_eq!(to_string(&ome).unwrap(), xml);
+    }
+
+    #[test]
+    fn uuid_parse_serialize_parse_is_stable() {
+        let first: OME = from_str(&uuid_ome_xml()).unwrap();
+        let xml = to_string(&first).unwrap();
+        let second: OME = from_str(&xml).unwrap();
+        assert_eq!(to_string(&second).unwrap(), xml);
+        // FileName is an attribute on the way out, never a child element
+        assert!(!xml.contains("<FileName"));
+        let uuid = |ome: &OME| ome.images[0].pixels.tiff_data[0].uuid.clone().unwrap();
+        assert_eq!(uuid(&first).file_name, uuid(&second).file_name);
+        assert_eq!(uuid(&first).value, uuid(&second).value);
+    }
+
+    #[test]
+    fn channel_samples_sets_samples_per_pixel() {
+        let dir = temp_dir("channel-samples");
+        let file = dir.join("rgb.ome.tif");
+        let description = to_string(&minimal_ome(1, 3, 1, DimensionOrder::XYZCT)).unwrap();
+        write_tiff(&file, 3, &description);
+        let file = file.to_str().unwrap();
+        let xml = output(&["--channel-samples", "3", file]);
+        assert!(xml.contains("SamplesPerPixel=\"3\""));
+        let ome: OME = from_str(&xml).unwrap();
+        let pixels = &ome.images[0].pixels;
+        assert_eq!(pixels.size_c, 3);
+        assert_eq!(pixels.channels.len(), 1);
+        assert_eq!(pixels.channels[0].samples_per_pixel, 3);
+        let (_, result) = run_args(&["--channel-samples", "2", file]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn concat_output_is_identical_across_runs() {
+        let dir = temp_dir("deterministic");
+        write_stack(&dir, 3, 3, &minimal_ome(1, 3, 1, DimensionOrder::XYCZT));
+        let args = [
+            "--emit-plane-position-from-stage",
+            "--normalize-dimension-order",
+        ];
+        let once = concat_stack(&dir, 3, &args);
+        assert_eq!(concat_stack(&dir, 3, &args).as_bytes(), once.as_bytes());
+    }
+
+    #[test]
+    fn coverage_reports_gaps_and_duplicates() {
+        let mut ome = minimal_ome(3, 1, 1, DimensionOrder::XYZCT);
+        one_file_per_z(&mut ome, |z| format!("s_z{}.ome.tif", z + 1));
+        assert!(check_tiff_data_coverage("a.ome.tif", &ome, &[]).is_empty());
+        ome.images[0].pixels.tiff_data[2].first_z = Some(1);
+        let messages: Vec<String> = check_tiff_data_coverage("a.ome.tif", &ome, &[])
+            .into_iter()
+            .map(|issue| issue.message)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "Pixels:0 plane Z=1 C=0 T=0 is referenced by 2 TiffData",
+                "Pixels:0 plane Z=2 C=0 T=0 is not referenced by any TiffData",
+            ]
+        );
+    }
+
+    #[test]
+    fn caption_before_the_ome_block_is_ignored() {
+        let dir = temp_dir("caption");
+        let xml = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT)).unwrap();
+        for (name, separator) in [("nul.ome.tif", "\0"), ("newline.ome.tif", "\n")] {
+            let file = dir.join(name);
+            write_tiff(
+                &file,
+                1,
+                &format!("Acquired on scope 2{}{}", separator, xml),
+            );
+            let description = get_image_description(file.to_str().unwrap(), 1).unwrap();
+            assert_eq!(description, xml);
+        }
+        let declared = format!("Scope 2\n<?xml version=\"1.0\"?>{}\ntrailer", xml);
+        assert_eq!(
+            extract_ome_block(&declared),
+            format!("<?xml version=\"1.0\"?>{}", xml)
+        );
+    }
+
+    #[test]
+    fn stage_positions_grid_places_four_tiles() {
+        let dir = temp_dir("grid");
+        let file = dir.join("tiles.ome.tif");
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        for i in 1..4 {
+            let mut image = ome.images[0].clone();
+            image.id = format!("Image:{}", i);
+            image.pixels.id = format!("Pixels:{}", i);
+            image.pixels.channels[0].id = format!("Channel:{}:0", i);
+            image.pixels.tiff_data[0].ifd = Some(i);
+            ome.images.push(image);
+        }
+        write_tiff(&file, 4, &to_string(&ome).unwrap());
+        let xml = output(&[
+            "--emit-stage-positions-grid",
+            "--grid-rows",
+            "2",
+            "--grid-cols",
+            "2",
+            "--tile-pitch-x",
+            "100",
+            "--tile-pitch-y",
+            "50",
+            file.to_str().unwrap(),
+        ]);
+        let ome: OME = from_str(&xml).unwrap();
+        let mut positions = Vec::new();
+        for image in &ome.images {
+            let label = image.stage_label.as_ref().unwrap();
+            let plane = &image.pixels.planes[0];
+            assert_eq!((plane.position_x, plane.position_y), (label.x, label.y));
+            positions.push((label.name.as_str(), label.x.unwrap(), label.y.unwrap()));
+        }
+        assert_eq!(
+            positions,
+            [
+                ("r0c0", 0.0, 0.0),
+                ("r0c1", 100.0, 0.0),
+                ("r1c0", 0.0, 50.0),
+                ("r1c1", 100.0, 50.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn master_and_companion_must_reference_each_other() {
+        let dir = temp_dir("master-companion");
+        let (master_uuid, companion_uuid) = (new_uuid(), new_uuid());
+        let mut master = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        master.uuid = Some(master_uuid.clone());
+        master.binary_only = Some(BinaryOnly {
+            metadata_file: "s.companion.ome".to_string(),
+            uuid: companion_uuid.clone(),
+        });
+        let master_file = dir.join("master.ome.tif");
+        write_tiff(&master_file, 1, &to_string(&master).unwrap());
+        let mut companion = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        one_file_per_z(&mut companion, |_| "master.ome.tif".to_string());
+        companion.images[0].pixels.tiff_data[0]
+            .uuid
+            .as_mut()
+            .unwrap()
+            .value = Some(master_uuid);
+        let companion_file = dir.join("s.companion.ome");
+        let validate = |companion: &OME| {
+            std::fs::write(&companion_file, to_string(companion).unwrap()).unwrap();
+            run_args(&[
+                "validate",
+                master_file.to_str().unwrap(),
+                "--companion",
+                companion_file.to_str().unwrap(),
+            ])
+        };
+        companion.uuid = Some(companion_uuid);
+        let (report, result) = validate(&companion);
+        assert_eq!(report, "");
+        result.unwrap();
+        companion.uuid = Some(new_uuid());
+        companion.images[0].pixels.tiff_data[0]
+            .uuid
+            .as_mut()
+            .unwrap()
+            .value = Some(new_uuid());
+        let (report, result) = validate(&companion);
+        assert!(result.is_err());
+        let errors: Vec<&str> = report.lines().collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("error: master.ome.tif BinaryOnly UUID "));
+        assert!(
+            errors[1].starts_with("error: s.companion.ome references master.ome.tif with UUID ")
+        );
+    }
+
+    #[test]
+    fn excluded_plane_has_no_tiff_data() {
+        let dir = temp_dir("exclude-planes");
+        write_stack(&dir, 3, 2, &minimal_ome(1, 2, 1, DimensionOrder::XYCZT));
+        let xml = concat_stack(&dir, 3, &["--exclude", "1,1,0"]);
+        let ome: OME = from_str(&xml).unwrap();
+        let planes: Vec<(usize, usize)> = tiff_data_planes(&ome.images[0].pixels, None)
+            .into_iter()
+            .map(|plane| (plane.selection.z, plane.selection.c))
+            .collect();
+        assert_eq!(planes.len(), 5);
+        assert!(!planes.contains(&(1, 1)));
+        let companion = dir.join("s.companion.ome");
+        std::fs::write(&companion, xml).unwrap();
+        let companion = companion.to_str().unwrap();
+        let (_, result) = run_args(&["validate", companion, "--require-tiffdata-coverage"]);
+        assert!(result.is_err());
+        let (report, result) = run_args(&[
+            "validate",
+            companion,
+            "--require-tiffdata-coverage",
+            "--exclude-planes",
+            "1,1,0",
+        ]);
+        assert_eq!(report, "");
+        result.unwrap();
+    }
+
+    #[test]
+    fn json_sidecar_matches_the_printed_summary() {
+        let dir = temp_dir("json-sidecar");
+        let file = dir.join("a.ome.xml");
+        let xml = to_string(&minimal_ome(2, 3, 1, DimensionOrder::XYZCT)).unwrap();
+        std::fs::write(&file, xml).unwrap();
+        let file = file.to_str().unwrap();
+        let sidecar = dir.join("info.json");
+        let sidecar = sidecar.to_str().unwrap();
+        let info = output(&["info", file, "--max-list", "1", "--json-sidecar", sidecar]);
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(sidecar).unwrap()).unwrap();
+        let image = &json["images"][0];
+        let heading = format!("{} {}\n", image["id"].as_str().unwrap(), image["name"]);
+        assert!(info.contains(&heading));
+        let size = &image["size"];
+        let dimensions = format!(
+            "  Dimensions: X={} Y={} Z={} C={} T={} ({})",
+            size["x"], size["y"], size["z"], size["c"], size["t"], image["dimension_order"]
+        );
+        assert!(info.contains(&dimensions.replace('"', "")));
+        let channels = image["channels"].as_array().unwrap().len();
+        assert!(info.contains(&format!("  Channels: {}\n", channels)));
+        assert_eq!(channels, 3);
+
+        let sidecar = dir.join("validate.json");
+        let sidecar = sidecar.to_str().unwrap();
+        let (report, result) = run_args(&[
+            "validate",
+            file,
+            "--report-missing-physical-sizes",
+            "--json-sidecar",
+            sidecar,
+        ]);
+        result.unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(sidecar).unwrap()).unwrap();
+        let printed: Vec<String> = json["issues"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|issue| {
+                format!(
+                    "{}: {}\n",
+                    issue["severity"].as_str().unwrap(),
+                    issue["message"].as_str().unwrap()
+                )
+            })
+            .collect();
+        assert!(!printed.is_empty());
+        assert_eq!(report, printed.concat());
+        assert_eq!(json["passed"], true);
+    }
+
+    #[test]
+    fn bit_planes_are_packed_eight_pixels_to_a_byte() {
+        let mut pixels = minimal_ome(1, 1, 1, DimensionOrder::XYZCT)
+            .images
+            .remove(0)
+            .pixels;
+        pixels.size_x = 10;
+        pixels.size_y = 10;
+        pixels.r#type = "bit".to_string();
+        assert_eq!(plane_bytes(&pixels), Some(13));
+        pixels.r#type = "uint16".to_string();
+        assert_eq!(plane_bytes(&pixels), Some(200));
+    }
+
+    #[test]
+    fn invalid_acquisition_date_warns() {
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        ome.images[0].acquisition_date = Some("2021-03-04T05:06:07".to_string());
+        assert!(check_acquisition_dates(&ome).is_empty());
+        ome.images[0].acquisition_date = Some("04/03/2021".to_string());
+        let issues = check_acquisition_dates(&ome);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity, Severity::Warning);
+        assert!(issues[0].message.starts_with("Image:0 AcquisitionDate "));
+    }
+
+    #[test]
+    fn plane_count_mismatch_is_reported_per_file() {
+        let dir = temp_dir("plane-count");
+        let mut ome = minimal_ome(3, 1, 1, DimensionOrder::XYZCT);
+        one_file_per_z(&mut ome, |z| format!("s_z{}.ome.tif", z + 1));
+        let companion = dir.join("s.companion.ome");
+        std::fs::write(&companion, to_string(&ome).unwrap()).unwrap();
+        for z in 1..=3 {
+            write_tiff(&dir.join(format!("s_z{}.ome.tif", z)), 1, "");
+        }
+        let companion = companion.to_str().unwrap();
+        let args = ["validate", companion, "--verify-plane-count-per-file"];
+        run_args(&args).1.unwrap();
+        write_tiff(&dir.join("s_z2.ome.tif"), 2, "");
+        let (report, result) = run_args(&args);
+        assert!(result.is_err());
+        assert_eq!(
+            report,
+            format!(
+                "error: {} has 2 IFDs but its TiffData declare 1 planes\n",
+                dir.join("s_z2.ome.tif").display()
+            )
+        );
+    }
+
+    #[test]
+    fn group_tiff_data_by_file_keeps_one_plane_per_plane() {
+        let dir = temp_dir("group-by-file");
+        write_stack(&dir, 3, 2, &minimal_ome(1, 2, 1, DimensionOrder::XYCZT));
+        let xml = concat_stack(&dir, 3, &["--group-tiff-data-by-file"]);
+        let ome: OME = from_str(&xml).unwrap();
+        let pixels = &ome.images[0].pixels;
+        let files: Vec<(&str, Option<usize>, Option<usize>)> = pixels
+            .tiff_data
+            .iter()
+            .map(|t| {
+                (
+                    t.uuid.as_ref().unwrap().file_name.as_str(),
+                    t.ifd,
+                    t.plane_count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("s_z1.ome.tif", Some(0), Some(2)),
+                ("s_z2.ome.tif", Some(0), Some(2)),
+                ("s_z3.ome.tif", Some(0), Some(2)),
+            ]
+        );
+        let planes: Vec<(usize, usize)> =
+            pixels.planes.iter().map(|p| (p.the_z, p.the_c)).collect();
+        assert_eq!(planes, [(0, 0), (0, 1), (1, 0), (1, 1), (2, 0), (2, 1)]);
+    }
+
+    #[test]
+    fn assert_shape_checks_the_given_axes() {
+        let pixels = minimal_ome(10, 2, 1, DimensionOrder::XYZCT)
+            .images
+            .remove(0)
+            .pixels;
+        let shape = |s: &str| s.parse::<Shape>().unwrap();
+        shape("z=10,c=2,t=1").check(&pixels).unwrap();
+        shape("x=4").check(&pixels).unwrap();
+        let error = shape("z=10,c=3,t=2").check(&pixels).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Pixels:0 has shape c=2 (expected 3), t=1 (expected 2)"
+        );
+        assert!("z=ten".parse::<Shape>().is_err());
+    }
+
+    #[test]
+    fn minimal_tiff_data_covers_the_planes_at_their_ifds() {
+        let mut ome = minimal_ome(2, 2, 1, DimensionOrder::XYZCT);
+        ome.images[0].pixels.tiff_data = [0, 2]
+            .map(|ifd| TiffData {
+                ifd: Some(ifd),
+                plane_count: Some(2),
+                first_c: None,
+                first_z: None,
+                first_t: None,
+                uuid: None,
+            })
+            .to_vec();
+        let xml = to_string(&ome).unwrap();
+        assert!(xml.contains(r#"<TiffData IFD="2" PlaneCount="2"/>"#));
+        let ome: OME = from_str(&xml).unwrap();
+        let pixels = &ome.images[0].pixels;
+        assert_eq!(pixels.tiff_data.len(), 2);
+        assert!(pixels.tiff_data[1].first_z.is_none());
+        assert_eq!(
+            plane_ifds(pixels),
+            [
+                ((0, 0, 0), 0),
+                ((0, 1, 0), 2),
+                ((1, 0, 0), 1),
+                ((1, 1, 0), 3)
+            ]
+        );
+        assert!(check_tiff_data_coverage("a.ome.tif", &ome, &[]).is_empty());
+    }
+
+    #[test]
+    fn minimal_tiff_data_of_another_file_starts_at_the_first_plane() {
+        let mut ome = minimal_ome(2, 2, 1, DimensionOrder::XYZCT);
+        let tiff_data = |ifd, file_name: &str| TiffData {
+            ifd: Some(ifd),
+            plane_count: Some(2),
+            first_c: None,
+            first_z: None,
+            first_t: None,
+            uuid: Some(Uuid {
+                file_name: file_name.to_string(),
+                value: None,
+            }),
+        };
+        ome.images[0].pixels.tiff_data = vec![tiff_data(0, "a.ome.tif"), tiff_data(2, "a.ome.tif")];
+        let first_planes = |ome: &OME| -> Vec<_> {
+            tiff_data_planes(&ome.images[0].pixels, Some("dir/a.ome.tif"))
+                .iter()
+                .map(|plane| (plane.selection.z, plane.selection.c))
+                .collect()
+        };
+        assert_eq!(first_planes(&ome), [(0, 0), (1, 0), (0, 1), (1, 1)]);
+        assert!(check_tiff_data_coverage("dir/a.ome.tif", &ome, &[]).is_empty());
+        ome.images[0].pixels.tiff_data[1] = tiff_data(2, "b.ome.tif");
+        assert_eq!(first_planes(&ome), [(0, 0), (1, 0), (0, 0), (1, 0)]);
+        let issues = check_tiff_data_coverage("dir/a.ome.tif", &ome, &[]);
+        assert_eq!(issues[0].severity, Severity::Warning);
+        assert_eq!(
+            issues[0].message,
+            "Pixels:0 TiffData at IFD 2 of b.ome.tif has no FirstZ/FirstC/FirstT, so it starts at the first plane"
+        );
+        assert!(issues[1..]
+            .iter()
+            .all(|issue| issue.severity == Severity::Error));
+    }
+
+    #[test]
+    fn reencode_pretty_keeps_unmodeled_metadata() {
+        let dir = temp_dir("reencode-pretty");
+        let file = dir.join("a.ome.xml");
+        let xml = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT))
+            .unwrap()
+            .replacen(
+                "<Image ",
+                r#"<Experimenter ID="Experimenter:0" FirstName="Ada"/><Image "#,
+                1,
+            );
+        std::fs::write(&file, xml).unwrap();
+        let file = file.to_str().unwrap();
+        let pretty = output(&["--reencode-pretty", file]);
+        assert!(pretty.contains(r#"<Experimenter ID="Experimenter:0" FirstName="Ada""#));
+        assert!(pretty.lines().count() > 3);
+        let (_, result) = run_args(&["--reencode-pretty", "--normalize-dimension-order", file]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unsupported_schema_version_is_detected() {
+        let dir = temp_dir("schema-version");
+        let file = dir.join("old.ome.xml");
+        let xml = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT))
+            .unwrap()
+            .replace("OME/2016-06", "OME/2015-01");
+        std::fs::write(&file, &xml).unwrap();
+        let file = file.to_str().unwrap();
+        let info = output(&["info", file]);
+        assert!(info.starts_with("Schema: 2015-01\n"));
+        let ome: OME = from_str(&xml).unwrap();
+        assert_eq!(schema_version(&ome), Some("2015-01"));
+        check_schema_version(&ome, false, &Warnings { json: false }).unwrap();
+        let error = check_schema_version(&ome, true, &Warnings { json: false }).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "OME schema 2015-01 is not supported (expected 2016-06), elements may be misread"
+        );
+        let (_, result) = run_args(&["--strict", "info", file]);
+        assert!(result.is_err());
+        let mut ome = ome;
+        ome.xmlns = None;
+        check_schema_version(&ome, true, &Warnings { json: false }).unwrap();
+        ome.xmlns = Some("urn:example".to_string());
+        let error = check_schema_version(&ome, true, &Warnings { json: false }).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown OME schema urn:example");
+    }
+
+    #[test]
+    fn physical_sizes_are_copied_from_the_reference() {
+        let dir = temp_dir("physical-sizes-from");
+        let mut reference = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        let pixels = &mut reference.images[0].pixels;
+        pixels.physical_size_x = Some(0.25);
+        pixels.physical_size_x_unit = Some(LengthUnit("µm"));
+        pixels.physical_size_y = Some(0.5);
+        pixels.physical_size_y_unit = Some(LengthUnit("µm"));
+        pixels.physical_size_z = Some(2.0);
+        pixels.physical_size_z_unit = Some(LengthUnit("nm"));
+        let reference_file = dir.join("reference.ome.xml");
+        std::fs::write(&reference_file, to_string(&reference).unwrap()).unwrap();
+        let target = dir.join("target.ome.tif");
+        let description = to_string(&minimal_ome(1, 1, 1, DimensionOrder::XYZCT)).unwrap();
+        write_tiff(&target, 1, &description);
+        let xml = output(&[
+            "--physical-sizes-from",
+            reference_file.to_str().unwrap(),
+            target.to_str().unwrap(),
+        ]);
+        let ome: OME = from_str(&xml).unwrap();
+        let pixels = &ome.images[0].pixels;
+        assert_eq!(pixels.physical_size_x, Some(0.25));
+        assert_eq!(pixels.physical_size_y, Some(0.5));
+        assert_eq!(pixels.physical_size_z, Some(2.0));
+        assert_eq!(pixels.physical_size_z_unit, Some(LengthUnit("nm")));
+        std::fs::write(&reference_file, description).unwrap();
+        let (_, result) = run_args(&[
+            "--physical-sizes-from",
+            reference_file.to_str().unwrap(),
+            target.to_str().unwrap(),
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn interleaved_rgb_has_one_ifd_per_z() {
+        let dir = temp_dir("interleaved");
+        write_stack(&dir, 2, 1, &minimal_ome(1, 3, 1, DimensionOrder::XYCZT));
+        let xml = concat_stack(&dir, 2, &["--interleaved"]);
+        assert!(xml.contains("Interleaved=\"true\""));
+        let ome: OME = from_str(&xml).unwrap();
+        let pixels = &ome.images[0].pixels;
+        assert_eq!(pixels.size_c, 3);
+        assert_eq!(pixels.channels.len(), 1);
+        assert_eq!(pixels.channels[0].samples_per_pixel, 3);
+        let ifds: Vec<(&str, Option<usize>)> = pixels
+            .tiff_data
+            .iter()
+            .map(|t| (t.uuid.as_ref().unwrap().file_name.as_str(), t.ifd))
+            .collect();
+        assert_eq!(ifds, [("s_z1.ome.tif", Some(0)), ("s_z2.ome.tif", Some(0))]);
+        assert_eq!(tiff_data_planes(pixels, None).len(), 2);
+    }
+
+    #[test]
+    fn ome_beyond_the_scan_limit_is_not_found() {
+        let dir = temp_dir("scan-limit");
+        let file = dir.join("last.ome.tif");
+        let description = to_string(&minimal_ome(5, 1, 1, DimensionOrder::XYZCT)).unwrap();
+        write_tiff_with(&file, 5, |i, encoder| {
+            if i == 4 {
+                encoder
+                    .write_tag(
+                        tiff::tags::Tag::ImageDescription,
+                        Utf8Ascii(description.as_str()),
+                    )
+                    .unwrap();
+            }
+        });
+        let file = file.to_str().unwrap();
+        assert_eq!(get_image_description(file, 5).unwrap(), description);
+        let error = get_image_description(file, 4).unwrap_err().to_string();
+        assert!(error.starts_with("No OME-XML found in the ImageDescription of the first 4 IFDs"));
+        let (_, result) = run_args(&["--tail-ifd-scan-limit", "4", file]);
+        assert!(result.is_err());
+        assert!(Cli::try_parse_from(["omecat", "--tail-ifd-scan-limit", "0", file]).is_err());
+    }
+
+    #[test]
+    fn numpy_info_gives_the_dtype_and_shape() {
+        let dir = temp_dir("numpy");
+        let file = dir.join("a.ome.xml");
+        let mut ome = minimal_ome(5, 2, 3, DimensionOrder::XYZCT);
+        ome.images[0].pixels.physical_size_x = Some(0.25);
+        std::fs::write(&file, to_string(&ome).unwrap()).unwrap();
+        let file = file.to_str().unwrap();
+        assert_eq!(
+            output(&["info", file, "--numpy"]),
+            "{'shape': (3, 2, 5, 3, 4), 'dtype': 'uint16', 'scale': (1.0, 1.0, 1.0, 1.0, 0.25)}\n"
+        );
+        ome.images[0].pixels.physical_size_y = Some(f64::NAN);
+        std::fs::write(file, to_string(&ome).unwrap()).unwrap();
+        assert!(output(&["info", file, "--numpy"]).contains("(1.0, 1.0, 1.0, float('nan'), 0.25)"));
+        assert_eq!(python_float(0.5), "0.5");
+        assert_eq!(python_float(f64::NEG_INFINITY), "-float('inf')");
+        assert_eq!(numpy_dtype("bit").unwrap(), "bool");
+        assert_eq!(numpy_dtype("double").unwrap(), "float64");
+        assert!(numpy_dtype("uint64").is_err());
+        assert!(numpy_dtype("").is_err());
+        let xml = to_string(&ome).unwrap().replace(" Type=\"uint16\"", "");
+        std::fs::write(file, xml).unwrap();
+        let (_, result) = run_args(&["info", file, "--numpy"]);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Pixels:0: Pixel type \"\" has no numpy dtype"
+        );
+    }
+
+    #[test]
+    fn channels_are_merged_from_the_reference() {
+        let dir = temp_dir("merge-channels");
+        let mut reference = minimal_ome(1, 3, 1, DimensionOrder::XYZCT);
+        let definitions = [("DAPI", -16776961), ("GFP", 16711935), ("mCherry", -65281)];
+        for (channel, (name, color)) in reference.images[0]
+            .pixels
+            .channels
+            .iter_mut()
+            .zip(definitions)
+        {
+            channel.id = format!("Channel:ref:{}", name);
+            channel.name = Some(name.to_string());
+            channel.color = Some(color);
+        }
+        let reference_file = dir.join("reference.ome.xml");
+        std::fs::write(&reference_file, to_string(&reference).unwrap()).unwrap();
+        let target = dir.join("target.ome.tif");
+        let description = to_string(&minimal_ome(1, 3, 1, DimensionOrder::XYZCT)).unwrap();
+        write_tiff(&target, 3, &description);
+        let xml = output(&[
+            "--merge-channels-from",
+            reference_file.to_str().unwrap(),
+            target.to_str().unwrap(),
+        ]);
+        let ome: OME = from_str(&xml).unwrap();
+        let channels: Vec<(&str, Option<&str>, Option<i32>)> = ome.images[0]
+            .pixels
+            .channels
+            .iter()
+            .map(|c| (c.id.as_str(), c.name.as_deref(), c.color))
+            .collect();
+        assert_eq!(
+            channels,
+            [
+                ("Channel:0:0", Some("DAPI"), Some(-16776961)),
+                ("Channel:0:1", Some("GFP"), Some(16711935)),
+                ("Channel:0:2", Some("mCherry"), Some(-65281)),
+            ]
+        );
+        let two = to_string(&minimal_ome(1, 2, 1, DimensionOrder::XYZCT)).unwrap();
+        write_tiff(&target, 2, &two);
+        let (_, result) = run_args(&[
+            "--merge-channels-from",
+            reference_file.to_str().unwrap(),
+            target.to_str().unwrap(),
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn root_uuid_roundtrips_apart_from_the_tiff_data_uuids() {
+        let dir = temp_dir("root-uuid");
+        let file = dir.join("a.ome.tif");
+        let mut ome: OME = from_str(&uuid_ome_xml()).unwrap();
+        let root = "urn:uuid:33333333-3333-3333-3333-333333333333";
+        ome.uuid = Some(root.to_string());
+        write_tiff(&file, 1, &to_string(&ome).unwrap());
+        let file = file.to_str().unwrap();
+        let xml = output(&["--normalize-dimension-order", file]);
+        let parsed: OME = from_str(&xml).unwrap();
+        assert_eq!(parsed.uuid.as_deref(), Some(root));
+        let tiff_data_uuid = parsed.images[0].pixels.tiff_data[0].uuid.as_ref().unwrap();
+        assert_eq!(
+            tiff_data_uuid.value.as_deref(),
+            Some("urn:uuid:11111111-1111-1111-1111-111111111111")
+        );
+        let other = "urn:uuid:44444444-4444-4444-4444-444444444444";
+        let xml = output(&["--uuid", other, file]);
+        let parsed: OME = from_str(&xml).unwrap();
+        assert_eq!(parsed.uuid.as_deref(), Some(other));
+        let tiff_data_uuid = parsed.images[0].pixels.tiff_data[0].uuid.as_ref().unwrap();
+        assert_ne!(tiff_data_uuid.value.as_deref(), Some(other));
+    }
+
+    #[test]
+    fn split_writes_one_file_per_plane_and_a_valid_companion() {
+        let dir = temp_dir("split");
+        let file = dir.join("stack.ome.tif");
+        let description = to_string(&minimal_ome(2, 2, 1, DimensionOrder::XYZCT)).unwrap();
+        write_tiff(&file, 4, &description);
+        let xml = output(&[
+            "split",
+            file.to_str().unwrap(),
+            "--filename-template",
+            "plane_z{z}_c{c}.tif",
+        ]);
+        for (z, c) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let plane = dir.join(format!("plane_z{}_c{}.tif", z + 1, c + 1));
+            let reader = std::fs::File::open(plane).map(std::io::BufReader::new);
+            let mut decoder = tiff::decoder::Decoder::new(reader.unwrap()).unwrap();
+            assert!(!decoder.more_images());
+            // XYZCT stores plane (z, c) at IFD z + 2c, whose samples start at that index
+            let first = z as u16 + 2 * c as u16;
+            let expected: Vec<u16> = (0..12).map(|p| p + first).collect();
+            match decoder.read_image().unwrap() {
+                tiff::decoder::DecodingResult::U16(data) => assert_eq!(data, expected),
+                _ => panic!("expected uint16 samples"),
+            }
+        }
+        let ome: OME = from_str(&xml).unwrap();
+        assert_eq!(ome.images[0].pixels.tiff_data.len(), 4);
+        let companion = dir.join("stack.companion.ome");
+        std::fs::write(&companion, xml).unwrap();
+        let (report, result) = run_args(&[
+            "validate",
+            companion.to_str().unwrap(),
+            "--require-tiffdata-coverage",
+            "--verify-plane-count-per-file",
+            "--check-page-numbers",
+        ]);
+        assert_eq!(report, "");
+        result.unwrap();
+    }
+
+    #[test]
+    fn warnings_json_has_a_stable_schema() {
+        let json = Warnings { json: true };
+        let line = json.line(
+            Severity::Warning,
+            "channels-dropped",
+            Some("Pixels:0"),
+            "Pixels:0 has 3 channels but SizeC=2, dropping 1",
+        );
+        let warning: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            warning,
+            serde_json::json!({
+                "code": "channels-dropped",
+                "severity": "warning",
+                "id": "Pixels:0",
+                "message": "Pixels:0 has 3 channels but SizeC=2, dropping 1",
+            })
+        );
+        assert!(!line.contains('\n'));
+        let message = "Unknown OME schema urn:example";
+        assert_eq!(
+            json.line(Severity::Warning, "unknown-schema", None, message),
+            r#"{"code":"unknown-schema","message":"Unknown OME schema urn:example","severity":"warning"}"#
+        );
+        let text = Warnings { json: false };
+        assert_eq!(
+            text.line(Severity::Warning, "unknown-schema", None, message),
+            "warning: Unknown OME schema urn:example"
+        );
+        assert_eq!(
+            text.line(Severity::Error, "unknown-schema", None, message),
+            "error: Unknown OME schema urn:example"
+        );
+    }
+
+    #[test]
+    fn validate_prints_its_issues_as_json_with_a_code() {
+        let dir = temp_dir("validate-json");
+        let file = dir.join("a.ome.xml");
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        ome.images[0].pixels.physical_size_x = Some(0.5);
+        ome.images[0].pixels.physical_size_y = Some(0.5);
+        ome.images[0].pixels.physical_size_y_unit = Some(LengthUnit("nm"));
+        std::fs::write(&file, to_string(&ome).unwrap()).unwrap();
+        let file = file.to_str().unwrap();
+        let args = ["validate", file, "--report-missing-physical-sizes"];
+        let (report, result) = run_args(&args);
+        result.unwrap();
+        assert_eq!(
+            report,
+            "warning: Pixels:0 PhysicalSizeX has no unit, read as µm\n\
+             warning: Image:0 is uncalibrated along Z (no PhysicalSize)\n"
+        );
+        let (report, _) = run_args(&[&args[..], &["--warnings-json"]].concat());
+        let codes: Vec<String> = report
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|issue| issue["code"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(codes, ["unit-missing", "physical-size-missing"]);
+    }
+
+    #[test]
+    fn non_conforming_channel_id_is_reported() {
+        let mut ome = minimal_ome(1, 2, 1, DimensionOrder::XYZCT);
+        assert!(check_canonical_ids(&ome).is_empty());
+        ome.images[0].pixels.channels[1].id = "GFP".to_string();
+        let issues = check_canonical_ids(&ome);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity, Severity::Error);
+        assert_eq!(
+            issues[0].message,
+            "Channel ID \"GFP\" doesn't match the pattern Channel:... (or urn:lsid:...:Channel:...)"
+        );
+    }
+
+    #[test]
+    fn duplicate_image_ids_are_renumbered() {
+        let mut ome = minimal_ome(1, 1, 1, DimensionOrder::XYZCT);
+        ome.images.push(ome.images[0].clone());
+        assert_eq!(check_duplicate_ids(&ome).len(), 3);
+        renumber_duplicate_ids(&mut ome, &Warnings { json: false });
+        assert!(check_duplicate_ids(&ome).is_empty());
+        let ids: Vec<(&str, &str)> = element_ids(&ome);
+        assert_eq!(
+            ids,
+            [
+                ("Image", "Image:0"),
+                ("Pixels", "Pixels:0"),
+                ("Channel", "Channel:0:0"),
+                ("Image", "Image:1"),
+                ("Pixels", "Pixels:1"),
+                ("Channel", "Channel:1:0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn plane_metadata_csv_fills_the_planes() {
+        let dir = temp_dir("plane-metadata");
+        write_stack(&dir, 2, 1, &minimal_ome(1, 1, 1, DimensionOrder::XYZCT));
+        let csv = dir.join("planes.csv");
+        let concat = |csv_text: &str| {
+            std::fs::write(&csv, csv_text).unwrap();
+            run_concat(&dir, 2, &["--plane-metadata-csv", csv.to_str().unwrap()])
+        };
+        let (xml, result) =
+            concat("z,c,t,deltaT,exposure,posZ\n0,0,0,0.5,0.01,\n1,0,0,1.5,0.02,3\n");
+        result.unwrap();
+        let ome: OME = from_str(&xml).unwrap();
+        let planes: Vec<_> = ome.images[0]
+            .pixels
+            .planes
+            .iter()
+            .map(|p| (p.the_z, p.delta_t, p.exposure_time, p.position_z))
+            .collect();
+        assert_eq!(
+            planes,
+            [
+                (0, Some(0.5), Some(0.01), None),
+                (1, Some(1.5), Some(0.02), Some(3.0)),
+            ]
+        );
+        assert_eq!(
+            ome.images[0].pixels.planes[0].delta_t_unit.as_deref(),
+            Some("s")
+        );
+        let (_, result) = concat("z,c,t,deltaT\n0,0,0,1\n0,0,0,2\n");
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("is given on lines 2 and 3"));
+        let (_, result) = concat("z,c,t,deltaT\n5,0,0,1\n");
+        assert!(result.is_err());
+    }
+}